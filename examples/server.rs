@@ -1,3 +1,22 @@
-fn main() {
-    println!("test");
-}
\ No newline at end of file
+use std::net::{Ipv4Addr, SocketAddr};
+
+use rusty_enet::{event::EventType, host::Host};
+
+fn main() -> std::io::Result<()> {
+    let mut host = Host::create(Some(SocketAddr::from((Ipv4Addr::UNSPECIFIED, 7777))), 32, 2, 0, 0)?;
+    println!("listening on {}", host.address);
+
+    loop {
+        let Some(event) = host.service(1000)? else {
+            continue;
+        };
+
+        match event.event_type {
+            EventType::Connect => println!("peer {} connected from {}", event.peer, host.peer(event.peer).address),
+            EventType::Disconnect => println!("peer {} disconnected", event.peer),
+            EventType::DisconnectTimeout => println!("peer {} timed out", event.peer),
+            EventType::Receive => println!("peer {} sent {} bytes on channel {}", event.peer, event.packet.map_or(0, |packet| packet.data_length()), event.channel_id),
+            EventType::None => {}
+        }
+    }
+}
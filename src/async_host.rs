@@ -0,0 +1,235 @@
+//! Tokio front end for [`Host`], available with the `tokio` feature.
+//!
+//! [`AsyncHost::service`] waits on the host's socket without blocking the
+//! runtime, while [`PeerHandle`]s let other tasks send to and manage peers.
+//! The host sits behind a mutex that is never held across an await point.
+
+use std::{io, net::SocketAddr, sync::{Arc, Mutex, MutexGuard}, time::Duration};
+
+use tokio::{io::Interest, net::UdpSocket, sync::Notify};
+
+use crate::{event::Event, host::Host, packet::Packet, peer::{PeerError, PeerId, PeerState, PeerStats}};
+
+pub mod constants {
+    /// Longest the service loop sleeps before looking at retransmissions,
+    /// pings and timeouts again
+    pub const ASYNC_HOST_SERVICE_INTERVAL: u64 = 10;
+}
+
+struct Shared {
+    host: Mutex<Host>,
+    /// Wakes the service loop when commands were queued from elsewhere
+    notify: Notify,
+}
+
+impl Shared {
+    fn host(&self) -> MutexGuard<'_, Host> {
+        // a panic while the host was locked leaves it no worse off than any
+        // other early return from one of its methods
+        self.host.lock().unwrap_or_else(|poisoned| poisoned.into_inner())
+    }
+
+    fn with_host<R>(&self, f: impl FnOnce(&mut Host) -> R) -> R {
+        let result = f(&mut self.host());
+        self.notify.notify_one();
+        result
+    }
+}
+
+pub struct AsyncHost {
+    shared: Arc<Shared>,
+    socket: UdpSocket,
+}
+
+impl AsyncHost {
+    /// Creates a host like [`Host::create`]. Has to be called from within a
+    /// Tokio runtime.
+    pub fn create(address: Option<SocketAddr>, peer_count: usize, channel_limit: usize, incoming_bandwidth: u32, outgoing_bandwidth: u32) -> io::Result<Self> {
+        Self::new(Host::create(address, peer_count, channel_limit, incoming_bandwidth, outgoing_bandwidth)?)
+    }
+
+    /// Takes over an already configured host, which must own a socket. Has
+    /// to be called from within a Tokio runtime.
+    pub fn new(host: Host) -> io::Result<Self> {
+        let Some(socket) = &host.socket else {
+            return Err(io::Error::new(io::ErrorKind::Unsupported, "host has no socket"));
+        };
+        let socket = UdpSocket::from_std(socket.try_clone()?)?;
+
+        Ok(Self {
+            shared: Arc::new(Shared { host: Mutex::new(host), notify: Notify::new() }),
+            socket,
+        })
+    }
+
+    pub fn address(&self) -> SocketAddr {
+        self.shared.host().address
+    }
+
+    /// Locks the host for anything the handles do not cover. The guard must
+    /// not be held across an await point.
+    pub fn host(&self) -> MutexGuard<'_, Host> {
+        self.shared.host()
+    }
+
+    /// Starts connecting like [`Host::connect`]; the peer is usable once
+    /// `service` reports a `Connect` event for it
+    pub fn connect(&self, address: SocketAddr, channel_count: usize, data: u32) -> Option<PeerHandle> {
+        let id = self.shared.with_host(|host| host.connect(address, channel_count, data))?;
+        Some(self.peer(id))
+    }
+
+    /// Returns a handle to the peer in slot `id`, for instance the one an
+    /// event was reported for
+    pub fn peer(&self, id: PeerId) -> PeerHandle {
+        PeerHandle { shared: self.shared.clone(), id }
+    }
+
+    pub fn broadcast(&self, channel_id: u8, packet: Packet) {
+        self.shared.with_host(|host| host.broadcast(channel_id, packet));
+    }
+
+    /// Waits for the next event, sending queued commands and taking care of
+    /// retransmissions, pings and timeouts in the meantime.
+    pub async fn service(&mut self) -> io::Result<Event> {
+        loop {
+            if let Some(event) = self.service_once()? {
+                return Ok(event);
+            }
+
+            tokio::select! {
+                result = self.socket.readable() => result?,
+                _ = self.shared.notify.notified() => {}
+                _ = tokio::time::sleep(Duration::from_millis(constants::ASYNC_HOST_SERVICE_INTERVAL)) => {}
+            }
+        }
+    }
+
+    /// Runs one pass of the host without waiting
+    fn service_once(&self) -> io::Result<Option<Event>> {
+        let mut host = self.shared.host();
+
+        // the host reads through its own handle to the socket, so readiness
+        // is only given up once a peek shows nothing is left to read
+        let result = self.socket.try_io(Interest::READABLE, || {
+            let event = host.service(0)?;
+            if event.is_some() {
+                return Ok(event);
+            }
+
+            let Some(socket) = &host.socket else {
+                return Ok(None);
+            };
+
+            match socket.peek_from(&mut [0]) {
+                Err(err) if err.kind() == io::ErrorKind::WouldBlock => Err(err),
+                _ => Ok(None),
+            }
+        });
+
+        match result {
+            Err(err) if err.kind() == io::ErrorKind::WouldBlock => host.service(0),
+            result => result,
+        }
+    }
+}
+
+/// Cheaply cloneable handle to a peer of an [`AsyncHost`].
+///
+/// Commands queued through a handle are sent by the task running
+/// `AsyncHost::service`. A handle refers to a peer slot, which is reused
+/// for a new connection once its peer has disconnected.
+#[derive(Clone)]
+pub struct PeerHandle {
+    shared: Arc<Shared>,
+    id: PeerId,
+}
+
+impl PeerHandle {
+    pub fn id(&self) -> PeerId {
+        self.id
+    }
+
+    pub fn address(&self) -> SocketAddr {
+        self.shared.host().peer(self.id).address
+    }
+
+    pub fn state(&self) -> PeerState {
+        self.shared.host().peer(self.id).state()
+    }
+
+    pub fn stats(&self) -> PeerStats {
+        self.shared.host().peer(self.id).stats()
+    }
+
+    pub fn send(&self, channel_id: u8, packet: Packet) -> Result<(), PeerError> {
+        self.shared.with_host(|host| host.send(self.id, channel_id, packet))
+    }
+
+    pub fn ping(&self) {
+        self.shared.with_host(|host| host.peer_mut(self.id).ping());
+    }
+
+    pub fn disconnect(&self, data: u32) -> Result<(), PeerError> {
+        self.shared.with_host(|host| host.disconnect(self.id, data))
+    }
+
+    pub fn disconnect_later(&self, data: u32) -> Result<(), PeerError> {
+        self.shared.with_host(|host| host.disconnect_later(self.id, data))
+    }
+
+    pub fn disconnect_now(&self, data: u32) -> Result<(), PeerError> {
+        self.shared.with_host(|host| host.disconnect_now(self.id, data))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::net::Ipv4Addr;
+
+    use super::*;
+    use crate::{event::EventType, packet::constants::PACKET_FLAG_RELIABLE};
+
+    fn localhost() -> Option<SocketAddr> {
+        Some(SocketAddr::from((Ipv4Addr::LOCALHOST, 0)))
+    }
+
+    #[tokio::test]
+    async fn peers_are_driven_from_other_tasks() {
+        let mut server = AsyncHost::create(localhost(), 1, 1, 0, 0).unwrap();
+        let mut client = AsyncHost::create(localhost(), 1, 1, 0, 0).unwrap();
+        let peer = client.connect(server.address(), 1, 0).unwrap();
+
+        let client_task = tokio::spawn(async move {
+            let mut events = Vec::new();
+            loop {
+                let event = client.service().await.unwrap();
+                events.push(event.event_type);
+                if event.event_type == EventType::Disconnect {
+                    return events;
+                }
+            }
+        });
+
+        let result = tokio::time::timeout(Duration::from_secs(5), async {
+            let event = server.service().await.unwrap();
+            assert_eq!(event.event_type, EventType::Connect);
+
+            let sender = peer.clone();
+            tokio::spawn(async move { sender.send(0, Packet::from_vec(b"hello".to_vec(), PACKET_FLAG_RELIABLE)) }).await.unwrap().unwrap();
+
+            let event = server.service().await.unwrap();
+            assert_eq!(event.event_type, EventType::Receive);
+            assert_eq!(&event.packet.unwrap().data[..], b"hello");
+
+            server.peer(event.peer).disconnect(7).unwrap();
+            let event = server.service().await.unwrap();
+            assert_eq!((event.event_type, event.data), (EventType::Disconnect, 0));
+        }).await;
+        assert!(result.is_ok(), "events were not delivered in time");
+
+        let events = tokio::time::timeout(Duration::from_secs(5), client_task).await.unwrap().unwrap();
+        assert_eq!(events, [EventType::Connect, EventType::Disconnect]);
+        assert_eq!(peer.state(), PeerState::Disconnected);
+    }
+}
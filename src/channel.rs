@@ -1,3 +1,7 @@
+use std::collections::VecDeque;
+
+use crate::{command::IncomingCommand, peer::constants::PEER_RELIABLE_WINDOWS};
+
 pub struct Channel {
     pub outgoing_reliable_seq_num: u16,
     pub outgoing_unreliable_seq_num: u16,
@@ -6,6 +10,21 @@ pub struct Channel {
     pub incoming_reliable_seq_num: u16,
     pub incoming_unreliable_seq_num: u16,
 
-    pub incoming_reliable_commands: Vec<()>, // ENetList
-    pub incoming_unreliable_commands: Vec<()>, // ENetList
-}
\ No newline at end of file
+    pub incoming_reliable_commands: VecDeque<IncomingCommand>, // ENetList
+    pub incoming_unreliable_commands: VecDeque<IncomingCommand>, // ENetList
+}
+
+impl Default for Channel {
+    fn default() -> Self {
+        Self {
+            outgoing_reliable_seq_num: 0,
+            outgoing_unreliable_seq_num: 0,
+            used_reliable_windows: 0,
+            reliable_windows: vec![0; PEER_RELIABLE_WINDOWS as usize].into_boxed_slice(),
+            incoming_reliable_seq_num: 0,
+            incoming_unreliable_seq_num: 0,
+            incoming_reliable_commands: VecDeque::new(),
+            incoming_unreliable_commands: VecDeque::new(),
+        }
+    }
+}
@@ -1,16 +1,13 @@
-use std::{cell::RefCell, rc::Rc};
-
 use crate::{packet::Packet, protocol::Protocol};
 
+#[derive(Debug, Clone, Copy)]
 pub struct Acknowledgement {
-    pub acknowledgement_list: Vec<()>, // ENetListNode
     pub sent_time: u32,
     pub command: Protocol,
 }
 
-#[derive(Default, Clone)]
-pub struct OutgoingCommand<'a> {
-    pub outgoing_command_list: Vec<()>, // ENetListNode
+#[derive(Default)]
+pub struct OutgoingCommand {
     pub reliable_seq_num: u16,
     pub unreliable_seq_num: u16,
     pub sent_time: u32,
@@ -20,16 +17,25 @@ pub struct OutgoingCommand<'a> {
     pub fragment_length: u32,
     pub send_attempts: u16,
     pub command: Protocol,
-    pub packet: Option<Rc<RefCell<Packet<'a>>>>,
+    pub packet: Option<Packet>,
+}
+
+impl Drop for OutgoingCommand {
+    fn drop(&mut self) {
+        // commands that delivered their packet take it out beforehand, any
+        // other command going away means the packet did not get through
+        if let Some(packet) = &self.packet {
+            packet.mark_dropped();
+        }
+    }
 }
 
-pub struct IncomingCommand<'a> {
-    pub incomingcommand_list: Vec<()>, // ENetListNode
+pub struct IncomingCommand {
     pub reliable_seq_num: u16,
     pub unreliable_seq_num: u16,
     pub command: Protocol,
     pub fragment_count: u32,
     pub fragments_remaining: u32,
     pub fragments: Vec<u32>, // u32*
-    pub packet: Packet<'a>,
+    pub packet: Packet,
 }
\ No newline at end of file
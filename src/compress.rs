@@ -1,3 +1,27 @@
-pub struct Compressor {
-    pub context: Option<()>, // void ptr
+use crate::range_coder::RangeCoder;
+
+/// Compresses the commands of outgoing datagrams and restores incoming ones.
+///
+/// Both ends of a connection have to use the same compressor. A host only
+/// marks a datagram as compressed when the result is smaller than the input.
+/// Compressors have to be `Send` so hosts can move between threads.
+pub trait Compressor: Send {
+    /// Compresses the concatenation of `in_buffers`, `in_limit` bytes in
+    /// total, into `out`. Returns the compressed length, or `None` if the
+    /// data does not fit in `out`.
+    fn compress(&mut self, in_buffers: &[&[u8]], in_limit: usize, out: &mut [u8]) -> Option<usize>;
+
+    /// Decompresses `input` into `out`, returning the decompressed length or
+    /// `None` if the input is invalid or does not fit in `out`.
+    fn decompress(&mut self, input: &[u8], out: &mut [u8]) -> Option<usize>;
+}
+
+impl Compressor for RangeCoder {
+    fn compress(&mut self, in_buffers: &[&[u8]], in_limit: usize, out: &mut [u8]) -> Option<usize> {
+        RangeCoder::compress(self, in_buffers, in_limit, out)
+    }
+
+    fn decompress(&mut self, input: &[u8], out: &mut [u8]) -> Option<usize> {
+        RangeCoder::decompress(self, input, out)
+    }
 }
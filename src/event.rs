@@ -1,16 +1,19 @@
-use crate::{packet::Packet, peer::Peer};
+use crate::{packet::Packet, peer::PeerId};
 
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
 pub enum EventType {
     None = 0,
     Connect = 1,
     Disconnect = 2,
-    Receive = 3
+    Receive = 3,
+    /// The peer stopped acknowledging reliable commands, see `Peer::set_timeout`
+    DisconnectTimeout = 4,
 }
 
-pub struct Event<'a> {
+pub struct Event {
     pub event_type: EventType,
-    pub peer: Peer<'a>,
+    pub peer: PeerId,
     pub channel_id: u8,
     pub data: u32,
-    pub packet: Packet<'a>
+    pub packet: Option<Packet>
 }
@@ -0,0 +1,2660 @@
+use std::{collections::{hash_map::RandomState, VecDeque}, hash::BuildHasher, io, mem, net::{Ipv4Addr, SocketAddr, UdpSocket}};
+
+use crate::{channel::Channel, compress::Compressor, event::{Event, EventType}, packet::{constants::{PACKET_FLAG_RELIABLE, PACKET_FLAG_UNRELIABLE_FRAGMENT, PACKET_FLAG_UNSEQUENCED}, Packet}, peer::{constants::*, Peer, PeerError, PeerId, PeerState}, protocol::{command_size, constants::{MAXIMUM_CHANNEL_COUNT, MAXIMUM_FRAGMENT_COUNT, MAXIMUM_MTU, MAXIMUM_PACKET_COMMANDS, MAXIMUM_PEER_ID, MAXIMUM_WINDOW_SIZE, MINIMUM_CHANNEL_COUNT, MINIMUM_MTU, MINIMUM_WINDOW_SIZE}, flags::{COMMAND_FLAG_ACKNOWLEDGE, COMMAND_FLAG_UNSEQUENCED, HEADER_FLAG_COMPRESSED, HEADER_FLAG_SENT_TIME, HEADER_SESSION_MASK, HEADER_SESSION_SHIFT}, Commands, Protocol, ProtocolAcknowledge, ProtocolBandwidthLimit, ProtocolCommand, ProtocolCommandHeader, ProtocolConnect, ProtocolDisconnect, ProtocolHeader, ProtocolSendFragment, ProtocolSendReliable, ProtocolSendUnreliable, ProtocolSendUnsequenced, ProtocolVerifyConnect}, range_coder::RangeCoder, socket::{Address, Socket}, time::{time_difference, time_greater_equal, time_less, Clock, SystemClock}};
+
+pub mod constants {
+    pub const HOST_RECEIVE_BUFFER_SIZE: u32         = 256 * 1024;
+    pub const HOST_SEND_BUFFER_SIZE: u32            = 256 * 1024;
+    pub const HOST_BANDWIDTH_THROTTLE_INTERVAL: u32 = 1000;
+    pub const HOST_DEFAULT_MTU: u32                 = 1392;
+    pub const HOST_DEFAULT_MAXIMUM_PACKET_SIZE: usize  = 32 * 1024 * 1024;
+    pub const HOST_DEFAULT_MAXIMUM_WAITING_DATA: usize = 32 * 1024 * 1024;
+
+    /// Upper bound on datagrams drained from the socket per service pass
+    pub const HOST_RECEIVE_BATCH: u32 = 256;
+}
+
+/// Computes a checksum over the concatenation of the given buffers
+pub type ChecksumCallback = fn(&[&[u8]]) -> u32;
+
+/// A datagram the host wants sent
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Transmit<A = SocketAddr> {
+    pub address: A,
+    pub data: Vec<u8>,
+}
+
+/// Raised by a command handler to stop processing the rest of a datagram
+struct CommandError;
+
+type HandleResult = Result<(), CommandError>;
+
+/// Reliable window ENet derives from the bandwidth of both ends, where 0
+/// stands for unlimited
+fn window_size(outgoing_bandwidth: u32, incoming_bandwidth: u32) -> u32 {
+    let window_size = if outgoing_bandwidth == 0 && incoming_bandwidth == 0 {
+        MAXIMUM_WINDOW_SIZE
+    } else if outgoing_bandwidth == 0 || incoming_bandwidth == 0 {
+        (outgoing_bandwidth.max(incoming_bandwidth) / PEER_WINDOW_SIZE_SCALE) * MINIMUM_WINDOW_SIZE
+    } else {
+        (outgoing_bandwidth.min(incoming_bandwidth) / PEER_WINDOW_SIZE_SCALE) * MINIMUM_WINDOW_SIZE
+    };
+
+    window_size.clamp(MINIMUM_WINDOW_SIZE, MAXIMUM_WINDOW_SIZE)
+}
+
+/// An ENet host.
+///
+/// The protocol itself never touches a socket or a clock: datagrams go in
+/// through `handle_datagram` and come out of `poll_transmit`, both taking the
+/// current time in milliseconds. Hosts made with `create` or `with_socket`
+/// own a socket and drive that state machine themselves in `service`.
+pub struct Host<S: Socket = UdpSocket> {
+    /// Socket used by `service` and `flush`; `None` for hosts made with `new`
+    pub socket: Option<S>,
+    pub address: S::Address,
+
+    pub incoming_bandwidth: u32,
+    pub outgoing_bandwidth: u32,
+    pub bandwidth_throttle_epoch: u32,
+    pub mtu: u32,
+    pub random_seed: u32,
+    pub recalculate_bandwidth_limits: bool,
+
+    pub peers: Vec<Peer<S::Address>>,
+    pub channel_limit: usize,
+    pub service_time: u32,
+    pub dispatch_queue: VecDeque<PeerId>,
+
+    pub header_flags: u16,
+    pub command_count: usize,
+    pub packet_size: usize,
+    pub packet_data: Vec<u8>, // commands of the datagram being assembled, without the header
+    pub transmits: VecDeque<Transmit<S::Address>>,
+
+    pub received_address: Option<S::Address>,
+    pub received_data: Box<[u8]>, // buffer of size protocol::constants::MAXIMUM_MTU
+    pub received_data_length: usize,
+
+    pub total_sent_data: u32,
+    pub total_sent_packets: u32,
+    pub total_received_data: u32,
+    pub total_received_packets: u32,
+
+    pub connected_peers: usize,
+    pub bandwidth_limited_peers: usize,
+    pub duplicate_peers: usize,
+    pub maximum_packet_size: usize,
+    pub maximum_waiting_data: usize,
+
+    pub compressor: Option<Box<dyn Compressor>>,
+    /// Adds a checksum to every datagram and drops those that fail it; both
+    /// ends must agree on it, `packet::crc32` being what ENet uses
+    pub checksum: Option<ChecksumCallback>,
+    /// Probes connected peers for a path MTU above the negotiated one and
+    /// raises the size of datagrams sent to them accordingly
+    pub mtu_probing: bool,
+    /// Where `service` and `flush` take the current time from
+    pub clock: Box<dyn Clock>,
+}
+
+impl Host {
+    /// Binds a UDP socket and allocates `peer_count` peer slots.
+    ///
+    /// Passing `None` as the address creates a client-only host bound to an
+    /// ephemeral port. A `channel_limit` of zero means the protocol maximum.
+    /// Bandwidths are in bytes per second, zero meaning unlimited.
+    pub fn create(address: Option<SocketAddr>, peer_count: usize, channel_limit: usize, incoming_bandwidth: u32, outgoing_bandwidth: u32) -> io::Result<Self> {
+        let socket = UdpSocket::bind(address.unwrap_or_else(|| SocketAddr::from((Ipv4Addr::UNSPECIFIED, 0))))?;
+        socket.set_nonblocking(true)?;
+
+        Self::with_socket(socket, peer_count, channel_limit, incoming_bandwidth, outgoing_bandwidth)
+    }
+}
+
+impl<S: Socket> Host<S> {
+    /// Creates a host sending and receiving through `socket`, which has to
+    /// be non-blocking
+    pub fn with_socket(socket: S, peer_count: usize, channel_limit: usize, incoming_bandwidth: u32, outgoing_bandwidth: u32) -> io::Result<Self> {
+        let mut host = Self::new(socket.local_address()?, peer_count, channel_limit, incoming_bandwidth, outgoing_bandwidth)?;
+        host.socket = Some(socket);
+        Ok(host)
+    }
+
+    /// Creates a host without a socket, to be driven through
+    /// `handle_datagram`, `poll_transmit`, `poll_event` and `poll_timeout`.
+    ///
+    /// `address` is where the caller exchanges the host's datagrams from.
+    pub fn new(address: S::Address, peer_count: usize, channel_limit: usize, incoming_bandwidth: u32, outgoing_bandwidth: u32) -> io::Result<Self> {
+        if peer_count > MAXIMUM_PEER_ID as usize {
+            return Err(io::Error::new(io::ErrorKind::InvalidInput, "peer count exceeds the protocol maximum"));
+        }
+
+        let channel_limit = if channel_limit == 0 || channel_limit > MAXIMUM_CHANNEL_COUNT as usize {
+            MAXIMUM_CHANNEL_COUNT as usize
+        } else {
+            channel_limit.max(MINIMUM_CHANNEL_COUNT as usize)
+        };
+
+        let random_seed = RandomState::new().hash_one(&address) as u32;
+        let random_seed = random_seed.rotate_right(16);
+
+        let mut peers = Vec::with_capacity(peer_count);
+        for id in 0..peer_count {
+            let mut peer = Peer::new(id as u16, address.clone());
+            peer.mtu = constants::HOST_DEFAULT_MTU;
+            peer.negotiated_mtu = constants::HOST_DEFAULT_MTU;
+            peers.push(peer);
+        }
+
+        Ok(Self {
+            socket: None,
+            address,
+            incoming_bandwidth,
+            outgoing_bandwidth,
+            bandwidth_throttle_epoch: 0,
+            mtu: constants::HOST_DEFAULT_MTU,
+            random_seed,
+            recalculate_bandwidth_limits: false,
+            peers,
+            channel_limit,
+            service_time: 0,
+            dispatch_queue: VecDeque::new(),
+            header_flags: 0,
+            command_count: 0,
+            packet_size: 0,
+            packet_data: Vec::with_capacity(MAXIMUM_MTU as usize),
+            transmits: VecDeque::new(),
+            received_address: None,
+            received_data: vec![0; MAXIMUM_MTU as usize].into_boxed_slice(),
+            received_data_length: 0,
+            total_sent_data: 0,
+            total_sent_packets: 0,
+            total_received_data: 0,
+            total_received_packets: 0,
+            connected_peers: 0,
+            bandwidth_limited_peers: 0,
+            duplicate_peers: MAXIMUM_PEER_ID as usize,
+            maximum_packet_size: constants::HOST_DEFAULT_MAXIMUM_PACKET_SIZE,
+            maximum_waiting_data: constants::HOST_DEFAULT_MAXIMUM_WAITING_DATA,
+            compressor: None,
+            checksum: None,
+            mtu_probing: false,
+            clock: Box::new(SystemClock::new()),
+        })
+    }
+
+    /// Replaces the system clock, for instance with a `MockClock` in tests.
+    /// Timers already running keep the timestamps of the previous clock, so
+    /// this belongs right after creating the host.
+    pub fn set_clock(&mut self, clock: Box<dyn Clock>) {
+        self.clock = clock;
+    }
+
+    /// Sets the compressor used for outgoing and incoming datagrams, `None`
+    /// disabling compression
+    pub fn compress(&mut self, compressor: Option<Box<dyn Compressor>>) {
+        self.compressor = compressor;
+    }
+
+    /// Compresses datagrams with the range coder that ENet ships with
+    pub fn compress_with_range_coder(&mut self) {
+        self.compress(Some(Box::new(RangeCoder::create())));
+    }
+
+    pub fn peer(&self, id: PeerId) -> &Peer<S::Address> {
+        &self.peers[id]
+    }
+
+    pub fn peer_mut(&mut self, id: PeerId) -> &mut Peer<S::Address> {
+        &mut self.peers[id]
+    }
+
+    pub fn channel_limit(&mut self, channel_limit: usize) {
+        self.channel_limit = if channel_limit == 0 || channel_limit > MAXIMUM_CHANNEL_COUNT as usize {
+            MAXIMUM_CHANNEL_COUNT as usize
+        } else {
+            channel_limit.max(MINIMUM_CHANNEL_COUNT as usize)
+        };
+    }
+
+    pub fn bandwidth_limit(&mut self, incoming_bandwidth: u32, outgoing_bandwidth: u32) {
+        self.incoming_bandwidth = incoming_bandwidth;
+        self.outgoing_bandwidth = outgoing_bandwidth;
+        self.recalculate_bandwidth_limits = true;
+    }
+
+    /// Redistributes the host's outgoing bandwidth between connected peers
+    /// and announces changed incoming limits with `BandwidthLimit` commands.
+    ///
+    /// Runs at most once per `HOST_BANDWIDTH_THROTTLE_INTERVAL` of service
+    /// time; `service` and `poll_transmit` call it automatically.
+    pub fn bandwidth_throttle(&mut self) {
+        let time_current = self.service_time;
+        let elapsed_time = time_current.wrapping_sub(self.bandwidth_throttle_epoch);
+        let mut peers_remaining = self.connected_peers as u32;
+        let mut data_total = u32::MAX;
+        let mut bandwidth = u32::MAX;
+        let mut needs_adjustment = self.bandwidth_limited_peers > 0;
+
+        if elapsed_time < constants::HOST_BANDWIDTH_THROTTLE_INTERVAL {
+            return;
+        }
+
+        self.bandwidth_throttle_epoch = time_current;
+
+        if peers_remaining == 0 {
+            return;
+        }
+
+        if self.outgoing_bandwidth != 0 {
+            bandwidth = self.outgoing_bandwidth.wrapping_mul(elapsed_time) / 1000;
+            data_total = self.peers.iter()
+                .filter(|peer| peer.state.is_connected())
+                .fold(0u32, |total, peer| total.wrapping_add(peer.outgoing_data_total));
+        }
+
+        while peers_remaining > 0 && needs_adjustment {
+            needs_adjustment = false;
+
+            let throttle = if data_total <= bandwidth {
+                PEER_PACKET_THROTTLE_SCALE
+            } else {
+                bandwidth.wrapping_mul(PEER_PACKET_THROTTLE_SCALE) / data_total
+            };
+
+            for peer in self.peers.iter_mut() {
+                if !peer.state.is_connected() || peer.incoming_bandwidth == 0 || peer.outgoing_bandwidth_throttle_epoch == time_current {
+                    continue;
+                }
+
+                let peer_bandwidth = peer.incoming_bandwidth.wrapping_mul(elapsed_time) / 1000;
+                if throttle.wrapping_mul(peer.outgoing_data_total) / PEER_PACKET_THROTTLE_SCALE <= peer_bandwidth {
+                    continue;
+                }
+
+                peer.packet_throttle_limit = (peer_bandwidth.wrapping_mul(PEER_PACKET_THROTTLE_SCALE) / peer.outgoing_data_total).max(1);
+                peer.packet_throttle = peer.packet_throttle.min(peer.packet_throttle_limit);
+                peer.outgoing_bandwidth_throttle_epoch = time_current;
+
+                peer.incoming_data_total = 0;
+                peer.outgoing_data_total = 0;
+
+                needs_adjustment = true;
+                peers_remaining -= 1;
+                bandwidth = bandwidth.wrapping_sub(peer_bandwidth);
+                data_total = data_total.wrapping_sub(peer_bandwidth);
+            }
+        }
+
+        if peers_remaining > 0 {
+            let throttle = if data_total <= bandwidth {
+                PEER_PACKET_THROTTLE_SCALE
+            } else {
+                bandwidth.wrapping_mul(PEER_PACKET_THROTTLE_SCALE) / data_total
+            };
+
+            for peer in self.peers.iter_mut() {
+                if !peer.state.is_connected() || peer.outgoing_bandwidth_throttle_epoch == time_current {
+                    continue;
+                }
+
+                peer.packet_throttle_limit = throttle;
+                peer.packet_throttle = peer.packet_throttle.min(peer.packet_throttle_limit);
+
+                peer.incoming_data_total = 0;
+                peer.outgoing_data_total = 0;
+            }
+        }
+
+        if !self.recalculate_bandwidth_limits {
+            return;
+        }
+
+        self.recalculate_bandwidth_limits = false;
+
+        let mut peers_remaining = self.connected_peers as u32;
+        let mut bandwidth = self.incoming_bandwidth;
+        let mut bandwidth_limit = 0;
+        let mut needs_adjustment = true;
+
+        if bandwidth != 0 {
+            while peers_remaining > 0 && needs_adjustment {
+                needs_adjustment = false;
+                bandwidth_limit = bandwidth / peers_remaining;
+
+                for peer in self.peers.iter_mut() {
+                    if !peer.state.is_connected() || peer.incoming_bandwidth_throttle_epoch == time_current {
+                        continue;
+                    }
+
+                    if peer.outgoing_bandwidth > 0 && peer.outgoing_bandwidth >= bandwidth_limit {
+                        continue;
+                    }
+
+                    peer.incoming_bandwidth_throttle_epoch = time_current;
+
+                    needs_adjustment = true;
+                    peers_remaining -= 1;
+                    bandwidth = bandwidth.wrapping_sub(peer.outgoing_bandwidth);
+                }
+            }
+        }
+
+        for peer in self.peers.iter_mut() {
+            if !peer.state.is_connected() {
+                continue;
+            }
+
+            let incoming_bandwidth = if peer.incoming_bandwidth_throttle_epoch == time_current { peer.outgoing_bandwidth } else { bandwidth_limit };
+            let command = Protocol::BandwidthLimit(ProtocolBandwidthLimit {
+                header: ProtocolCommandHeader { command: ProtocolCommand::BandwidthLimit as u8 | COMMAND_FLAG_ACKNOWLEDGE, channel_id: 0xFF, reliable_sequence_number: 0 },
+                incoming_bandwidth,
+                outgoing_bandwidth: self.outgoing_bandwidth,
+            });
+
+            peer.queue_outgoing_command(command, None, 0, 0);
+        }
+    }
+
+    /// Current time of the host's clock in milliseconds
+    pub fn time_get(&self) -> u32 {
+        self.clock.now()
+    }
+
+    /// Mulberry32, as used by ENet 1.3.18 for connect ids
+    fn random(&mut self) -> u32 {
+        self.random_seed = self.random_seed.wrapping_add(0x6D2B79F5);
+
+        let mut n = self.random_seed;
+        n = (n ^ (n >> 15)).wrapping_mul(n | 1);
+        n ^= n.wrapping_add((n ^ (n >> 7)).wrapping_mul(n | 61));
+        n ^ (n >> 14)
+    }
+
+    /// Starts connecting to a foreign host, returning the peer slot used.
+    ///
+    /// The connection is only usable once a `Connect` event is reported for
+    /// the returned peer. Returns `None` when every peer slot is taken.
+    pub fn connect(&mut self, address: S::Address, channel_count: usize, data: u32) -> Option<PeerId> {
+        let channel_count = channel_count.clamp(MINIMUM_CHANNEL_COUNT as usize, MAXIMUM_CHANNEL_COUNT as usize);
+        let id = self.peers.iter().position(|peer| peer.state == PeerState::Disconnected)?;
+
+        self.change_state(id, PeerState::Connecting);
+        let connect_id = self.random();
+
+        let peer = &mut self.peers[id];
+        peer.channels = (0..channel_count).map(|_| Channel::default()).collect();
+        peer.channel_count = channel_count;
+        peer.address = address;
+        peer.connect_id = connect_id;
+        peer.mtu = self.mtu;
+        peer.negotiated_mtu = peer.mtu;
+        peer.window_size = window_size(self.outgoing_bandwidth, 0);
+
+        let command = Protocol::Connect(ProtocolConnect {
+            header: ProtocolCommandHeader { command: ProtocolCommand::Connect as u8 | COMMAND_FLAG_ACKNOWLEDGE, channel_id: 0xFF, reliable_sequence_number: 0 },
+            outgoing_peer_id: peer.incoming_peer_id,
+            incoming_session_id: peer.incoming_session_id,
+            outgoing_session_id: peer.outgoing_session_id,
+            mtu: peer.mtu,
+            window_size: peer.window_size,
+            channel_count: channel_count as u32,
+            incoming_bandwidth: self.incoming_bandwidth,
+            outgoing_bandwidth: self.outgoing_bandwidth,
+            packet_throttle_interval: peer.packet_throttle_interval,
+            packet_throttle_acceleration: peer.packet_throttle_accel,
+            packet_throttle_deceleration: peer.packet_throttle_decel,
+            connect_id,
+            data,
+        });
+
+        peer.queue_outgoing_command(command, None, 0, 0);
+        Some(id)
+    }
+
+    /// Requests a graceful disconnect.
+    ///
+    /// A `Disconnect` event is generated once the foreign host acknowledges
+    /// the request.
+    pub fn disconnect(&mut self, id: PeerId, data: u32) -> Result<(), PeerError> {
+        let state = self.peers[id].state;
+        if matches!(state, PeerState::Disconnecting | PeerState::Disconnected | PeerState::AcknowledgingDisconnect | PeerState::Zombie) {
+            return Err(PeerError::InvalidState(state));
+        }
+
+        self.reset_peer_queues(id);
+
+        let flag = if state.is_connected() { COMMAND_FLAG_ACKNOWLEDGE } else { COMMAND_FLAG_UNSEQUENCED };
+        self.peers[id].queue_outgoing_command(Protocol::Disconnect(ProtocolDisconnect {
+            header: ProtocolCommandHeader { command: ProtocolCommand::Disconnect as u8 | flag, channel_id: 0xFF, reliable_sequence_number: 0 },
+            data,
+        }), None, 0, 0);
+
+        if state.is_connected() {
+            self.change_state(id, PeerState::Disconnecting);
+        } else {
+            // the notification is best effort, the peer goes away regardless
+            self.send_outgoing_commands(false);
+            let _ = self.write_transmits();
+            self.reset_peer(id);
+        }
+
+        Ok(())
+    }
+
+    /// Disconnects immediately, without waiting for an acknowledgement.
+    ///
+    /// No `Disconnect` event is generated for the peer.
+    pub fn disconnect_now(&mut self, id: PeerId, data: u32) -> Result<(), PeerError> {
+        let state = self.peers[id].state;
+        if state == PeerState::Disconnected {
+            return Err(PeerError::InvalidState(state));
+        }
+
+        if state != PeerState::Zombie && state != PeerState::Disconnecting {
+            self.reset_peer_queues(id);
+
+            self.peers[id].queue_outgoing_command(Protocol::Disconnect(ProtocolDisconnect {
+                header: ProtocolCommandHeader { command: ProtocolCommand::Disconnect as u8 | COMMAND_FLAG_UNSEQUENCED, channel_id: 0xFF, reliable_sequence_number: 0 },
+                data,
+            }), None, 0, 0);
+
+            self.send_outgoing_commands(false);
+            let _ = self.write_transmits();
+        }
+
+        self.reset_peer(id);
+        Ok(())
+    }
+
+    /// Disconnects once every queued outgoing packet has been acknowledged
+    pub fn disconnect_later(&mut self, id: PeerId, data: u32) -> Result<(), PeerError> {
+        let peer = &mut self.peers[id];
+        if peer.state.is_connected() && peer.has_outgoing_commands() {
+            peer.event_data = data;
+            self.change_state(id, PeerState::DisconnectLater);
+            Ok(())
+        } else {
+            self.disconnect(id, data)
+        }
+    }
+
+    /// Queues a packet to be sent to a connected peer on `channel_id`.
+    ///
+    /// Reliable packets are retransmitted until acknowledged, unsequenced ones
+    /// may arrive in any order, and everything else is delivered in order
+    /// with stale packets dropped. Packets that do not fit in the peer's MTU
+    /// are split into reliable fragments, or unreliable ones when
+    /// PACKET_FLAG_UNRELIABLE_FRAGMENT is set.
+    pub fn send(&mut self, id: PeerId, channel_id: u8, packet: Packet) -> Result<(), PeerError> {
+        let peer = &mut self.peers[id];
+        if peer.state != PeerState::Connected {
+            return Err(PeerError::InvalidState(peer.state));
+        }
+
+        let Some(channel) = peer.channels.get(channel_id as usize) else {
+            return Err(PeerError::InvalidChannel(channel_id));
+        };
+
+        if packet.data_length() > self.maximum_packet_size {
+            return Err(PeerError::PacketTooLarge(packet.data_length()));
+        }
+
+        // cut for the negotiated MTU, so fragments still fit once a probed MTU is given up on
+        let mut fragment_length = peer.negotiated_mtu as usize - mem::size_of::<ProtocolHeader>() - mem::size_of::<ProtocolSendFragment>();
+        if self.checksum.is_some() {
+            fragment_length -= mem::size_of::<u32>();
+        }
+
+        if packet.data_length() > fragment_length {
+            let total_length = packet.data_length();
+            let fragment_count = total_length.div_ceil(fragment_length);
+            if fragment_count > MAXIMUM_FRAGMENT_COUNT as usize {
+                return Err(PeerError::PacketTooLarge(total_length));
+            }
+
+            // unreliable fragments are only an option while the unreliable
+            // sequence has room for them, otherwise fall back to reliable ones
+            let (command, start_sequence_number) = if packet.flags & (PACKET_FLAG_RELIABLE | PACKET_FLAG_UNRELIABLE_FRAGMENT) == PACKET_FLAG_UNRELIABLE_FRAGMENT &&
+                                                      channel.outgoing_unreliable_seq_num < u16::MAX {
+                (ProtocolCommand::SendUnreliableFragment as u8, channel.outgoing_unreliable_seq_num.wrapping_add(1))
+            } else {
+                (ProtocolCommand::SendFragment as u8 | COMMAND_FLAG_ACKNOWLEDGE, channel.outgoing_reliable_seq_num.wrapping_add(1))
+            };
+
+            for (fragment_number, fragment_offset) in (0..total_length).step_by(fragment_length).enumerate() {
+                let data_length = fragment_length.min(total_length - fragment_offset) as u16;
+                let fragment = Protocol::SendFragment(ProtocolSendFragment {
+                    header: ProtocolCommandHeader { command, channel_id, reliable_sequence_number: 0 },
+                    start_sequence_number,
+                    data_length,
+                    fragment_count: fragment_count as u32,
+                    fragment_number: fragment_number as u32,
+                    total_length: total_length as u32,
+                    fragment_offset: fragment_offset as u32,
+                });
+
+                peer.queue_outgoing_command(fragment, Some(packet.clone()), fragment_offset as u32, data_length);
+            }
+
+            return Ok(());
+        }
+
+        let data_length = packet.data_length() as u16;
+        let command = if packet.flags & (PACKET_FLAG_RELIABLE | PACKET_FLAG_UNSEQUENCED) == PACKET_FLAG_UNSEQUENCED {
+            Protocol::SendUnsequenced(ProtocolSendUnsequenced {
+                header: ProtocolCommandHeader { command: ProtocolCommand::SendUnsequenced as u8 | COMMAND_FLAG_UNSEQUENCED, channel_id, reliable_sequence_number: 0 },
+                unsequenced_group: 0,
+                data_length,
+            })
+        } else if packet.flags & PACKET_FLAG_RELIABLE != 0 || channel.outgoing_unreliable_seq_num == u16::MAX {
+            Protocol::SendReliable(ProtocolSendReliable {
+                header: ProtocolCommandHeader { command: ProtocolCommand::SendReliable as u8 | COMMAND_FLAG_ACKNOWLEDGE, channel_id, reliable_sequence_number: 0 },
+                data_length,
+            })
+        } else {
+            Protocol::SendUnreliable(ProtocolSendUnreliable {
+                header: ProtocolCommandHeader { command: ProtocolCommand::SendUnreliable as u8, channel_id, reliable_sequence_number: 0 },
+                unreliable_sequence_number: 0,
+                data_length,
+            })
+        };
+
+        peer.queue_outgoing_command(command, Some(packet), 0, data_length);
+        Ok(())
+    }
+
+    /// Queues a packet for every connected peer. The payload is shared, not
+    /// copied, and peers that cannot take the packet are skipped.
+    pub fn broadcast(&mut self, channel_id: u8, packet: Packet) {
+        for id in 0..self.peers.len() {
+            if self.peers[id].state == PeerState::Connected {
+                let _ = self.send(id, channel_id, packet.clone());
+            }
+        }
+    }
+
+    /// Sends any queued packets immediately instead of waiting for `service`
+    pub fn flush(&mut self) -> io::Result<()> {
+        self.service_time = self.time_get();
+        self.send_outgoing_commands(false);
+        self.write_transmits()
+    }
+
+    /// Waits up to `timeout` milliseconds for an event and returns it.
+    ///
+    /// Incoming datagrams are processed and queued commands are sent as part
+    /// of the call; `Ok(None)` means the timeout elapsed without anything to
+    /// report.
+    pub fn service(&mut self, timeout: u32) -> io::Result<Option<Event>> {
+        if let Some(event) = self.dispatch_incoming_commands() {
+            return Ok(Some(event));
+        }
+
+        self.service_time = self.time_get();
+        let timeout = self.service_time.wrapping_add(timeout);
+
+        loop {
+            if time_difference(self.service_time, self.bandwidth_throttle_epoch) >= constants::HOST_BANDWIDTH_THROTTLE_INTERVAL {
+                self.bandwidth_throttle();
+            }
+
+            self.send_outgoing_commands(true);
+            self.write_transmits()?;
+            self.receive_incoming_commands()?;
+            self.send_outgoing_commands(true);
+            self.write_transmits()?;
+
+            if let Some(event) = self.dispatch_incoming_commands() {
+                return Ok(Some(event));
+            }
+
+            if time_greater_equal(self.service_time, timeout) {
+                return Ok(None);
+            }
+
+            self.service_time = self.time_get();
+            if time_greater_equal(self.service_time, timeout) {
+                return Ok(None);
+            }
+
+            if !self.wait(time_difference(timeout, self.service_time))? {
+                return Ok(None);
+            }
+
+            self.service_time = self.time_get();
+        }
+    }
+
+    /// Returns any event that is already queued without touching the socket
+    pub fn check_events(&mut self) -> Option<Event> {
+        self.dispatch_incoming_commands()
+    }
+
+    /// Processes a datagram received from `address` at time `now`
+    pub fn handle_datagram(&mut self, now: u32, address: S::Address, data: &[u8]) {
+        if data.len() > MAXIMUM_MTU as usize {
+            return;
+        }
+
+        self.service_time = now;
+        self.received_address = Some(address.clone());
+        self.received_data_length = data.len();
+
+        self.total_received_data = self.total_received_data.wrapping_add(data.len() as u32);
+        self.total_received_packets = self.total_received_packets.wrapping_add(1);
+
+        self.process_datagram(address, data);
+    }
+
+    /// Returns the next datagram to send at time `now`.
+    ///
+    /// Once the datagrams assembled so far have been taken, the next call
+    /// assembles new ones from the queued commands, retransmitting timed out
+    /// reliable commands and pinging idle peers along the way.
+    pub fn poll_transmit(&mut self, now: u32) -> Option<Transmit<S::Address>> {
+        if self.transmits.is_empty() {
+            self.service_time = now;
+            if time_difference(now, self.bandwidth_throttle_epoch) >= constants::HOST_BANDWIDTH_THROTTLE_INTERVAL {
+                self.bandwidth_throttle();
+            }
+
+            self.send_outgoing_commands(true);
+        }
+
+        self.transmits.pop_front()
+    }
+
+    /// Returns the next event for the application
+    pub fn poll_event(&mut self) -> Option<Event> {
+        self.dispatch_incoming_commands()
+    }
+
+    /// Returns the time at which `poll_transmit` has work to do even if no
+    /// datagram arrives in the meantime, or `None` if nothing is pending.
+    ///
+    /// Packets queued by the application are only picked up by the next
+    /// `poll_transmit` call and are not reflected here.
+    pub fn poll_timeout(&self) -> Option<u32> {
+        let mut timeout = None;
+        let mut schedule = |time: u32| {
+            if timeout.is_none_or(|timeout| time_less(time, timeout)) {
+                timeout = Some(time);
+            }
+        };
+
+        if !self.transmits.is_empty() {
+            schedule(self.service_time);
+        }
+
+        for peer in &self.peers {
+            if peer.state == PeerState::Disconnected || peer.state == PeerState::Zombie {
+                continue;
+            }
+
+            if !peer.acknowledgements.is_empty() {
+                schedule(self.service_time);
+            }
+
+            if !peer.sent_reliable_commands.is_empty() {
+                schedule(peer.next_timeout);
+            } else if peer.state == PeerState::Connected && peer.flags & PEER_FLAG_NO_PINGS == 0 {
+                schedule(peer.last_receive_time.wrapping_add(peer.ping_interval));
+            }
+
+            if self.mtu_probing && peer.state == PeerState::Connected && peer.mtu_probe == 0 &&
+               peer.mtu + PEER_MTU_PROBE_PRECISION < peer.mtu_probe_limit {
+                schedule(peer.mtu_probe_time);
+            }
+        }
+
+        if self.connected_peers > 0 {
+            schedule(self.bandwidth_throttle_epoch.wrapping_add(constants::HOST_BANDWIDTH_THROTTLE_INTERVAL));
+        }
+
+        timeout
+    }
+
+    fn bound_socket(&mut self) -> io::Result<&mut S> {
+        self.socket.as_mut().ok_or_else(|| io::Error::new(io::ErrorKind::Unsupported, "host has no socket"))
+    }
+
+    /// Hands the assembled datagrams to the socket, leaving them for
+    /// `poll_transmit` on hosts without one
+    fn write_transmits(&mut self) -> io::Result<()> {
+        let Some(socket) = &mut self.socket else {
+            return Ok(());
+        };
+
+        while let Some(transmit) = self.transmits.pop_front() {
+            match socket.send_to(&transmit.data, &transmit.address) {
+                Ok(_) => {}
+                // a full socket buffer behaves like any other lost datagram
+                Err(err) if err.kind() == io::ErrorKind::WouldBlock => {}
+                Err(err) => return Err(err),
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Blocks until the socket is readable or `timeout` milliseconds pass
+    fn wait(&mut self, timeout: u32) -> io::Result<bool> {
+        self.bound_socket()?.wait(timeout)
+    }
+
+    fn receive_incoming_commands(&mut self) -> io::Result<()> {
+        // the buffer is handed back once the datagrams have been processed,
+        // so the handlers are free to borrow the host mutably
+        let mut data = mem::take(&mut self.received_data);
+        let result = self.receive_into(&mut data);
+        self.received_data = data;
+        result
+    }
+
+    fn receive_into(&mut self, data: &mut [u8]) -> io::Result<()> {
+        for _ in 0..constants::HOST_RECEIVE_BATCH {
+            let (length, address) = match self.bound_socket()?.recv_from(data) {
+                Ok(received) => received,
+                Err(err) if err.kind() == io::ErrorKind::WouldBlock => return Ok(()),
+                // ICMP port unreachable from a previous send shows up here on some platforms
+                Err(err) if err.kind() == io::ErrorKind::ConnectionReset => continue,
+                Err(err) => return Err(err),
+            };
+
+            self.handle_datagram(self.service_time, address, &data[..length]);
+        }
+
+        Ok(())
+    }
+
+    /// Validates a datagram and processes its commands, silently dropping it
+    /// when anything about it is off
+    fn process_datagram(&mut self, address: S::Address, data: &[u8]) -> Option<()> {
+        let (header, mut header_size) = ProtocolHeader::decode(data).ok()?;
+        if self.checksum.is_some() {
+            header_size += mem::size_of::<u32>();
+        }
+
+        if header_size > data.len() {
+            return None;
+        }
+
+        let peer = self.validate_incoming_peer(&header, address, data.len())?;
+
+        let decompressed;
+        let commands = if header.flags() & HEADER_FLAG_COMPRESSED != 0 {
+            let compressor = self.compressor.as_mut()?;
+            let mut out = vec![0; MAXIMUM_MTU as usize - header_size];
+            let length = compressor.decompress(&data[header_size..], &mut out).filter(|&length| length > 0)?;
+            out.truncate(length);
+            decompressed = out;
+            &decompressed[..]
+        } else {
+            &data[header_size..]
+        };
+
+        // the checksum covers the uncompressed datagram with the connect id
+        // in place of the checksum itself
+        if let Some(checksum) = self.checksum {
+            let checksum_offset = header_size - mem::size_of::<u32>();
+            let desired_checksum = u32::from_be_bytes(data[checksum_offset..header_size].try_into().ok()?);
+
+            let seed = peer.map_or(0, |id| self.peers[id].connect_id);
+            let mut prefix = data[..header_size].to_vec();
+            prefix[checksum_offset..].copy_from_slice(&seed.to_be_bytes());
+
+            if checksum(&[&prefix, commands]) != desired_checksum {
+                return None;
+            }
+        }
+
+        self.handle_commands(&header, peer, commands);
+        Some(())
+    }
+
+    /// Resolves the peer a datagram is addressed to.
+    ///
+    /// Returns `None` when the datagram must be dropped and `Some(None)` when
+    /// it is not addressed to any peer yet, which only a connect may be.
+    fn validate_incoming_peer(&mut self, header: &ProtocolHeader, address: S::Address, length: usize) -> Option<Option<PeerId>> {
+        let peer_id = header.id();
+        if peer_id == MAXIMUM_PEER_ID as u16 {
+            return Some(None);
+        }
+
+        let peer = self.peers.get_mut(peer_id as usize)?;
+        if peer.state == PeerState::Disconnected || peer.state == PeerState::Zombie ||
+           peer.address != address || (peer.outgoing_peer_id < MAXIMUM_PEER_ID as u16 && header.session_id() != peer.incoming_session_id) {
+            return None;
+        }
+
+        peer.address = address;
+        peer.incoming_data_total = peer.incoming_data_total.wrapping_add(length as u32);
+
+        Some(Some(peer_id as PeerId))
+    }
+
+    /// Runs every command of a datagram through its handler, acknowledging
+    /// the reliable ones. Processing stops at the first malformed command.
+    fn handle_commands(&mut self, header: &ProtocolHeader, mut peer: Option<PeerId>, data: &[u8]) {
+        for command in Commands::new(data) {
+            let Ok((command, payload)) = command else {
+                break;
+            };
+
+            let Some(command_number) = command.command() else {
+                break;
+            };
+
+            let result = match (command_number, peer) {
+                (ProtocolCommand::Connect, None) => {
+                    peer = self.handle_connect(&command);
+                    peer.map(|_| ()).ok_or(CommandError)
+                }
+                (ProtocolCommand::Acknowledge, Some(id)) => self.handle_acknowledge(id, &command),
+                (ProtocolCommand::VerifyConnect, Some(id)) => self.handle_verify_connect(id, &command),
+                (ProtocolCommand::Disconnect, Some(id)) => self.handle_disconnect(id, &command),
+                (ProtocolCommand::Ping, Some(id)) => self.handle_ping(id),
+                (ProtocolCommand::BandwidthLimit, Some(id)) => self.handle_bandwidth_limit(id, &command),
+                (ProtocolCommand::ThrottleConfigure, Some(id)) => self.handle_throttle_configure(id, &command),
+                (ProtocolCommand::SendReliable, Some(id)) => self.handle_send(id, &command, payload, PACKET_FLAG_RELIABLE),
+                (ProtocolCommand::SendUnreliable, Some(id)) => self.handle_send(id, &command, payload, 0),
+                (ProtocolCommand::SendUnsequenced, Some(id)) => self.handle_send_unsequenced(id, &command, payload),
+                (ProtocolCommand::SendFragment | ProtocolCommand::SendUnreliableFragment, Some(id)) => self.handle_send_fragment(id, &command, payload),
+                _ => Err(CommandError),
+            };
+
+            if result.is_err() {
+                break;
+            }
+
+            let Some(id) = peer else {
+                break;
+            };
+
+            if command.header().command & COMMAND_FLAG_ACKNOWLEDGE != 0 {
+                if header.flags() & HEADER_FLAG_SENT_TIME == 0 {
+                    break;
+                }
+
+                let peer = &mut self.peers[id];
+                match peer.state {
+                    PeerState::Disconnecting | PeerState::AcknowledgingConnect | PeerState::Disconnected | PeerState::Zombie => {}
+                    PeerState::AcknowledgingDisconnect => {
+                        if command_number == ProtocolCommand::Disconnect {
+                            peer.queue_acknowledgement(&command, header.sent_time);
+                        }
+                    }
+                    _ => peer.queue_acknowledgement(&command, header.sent_time),
+                }
+            }
+        }
+    }
+
+    fn handle_connect(&mut self, command: &Protocol) -> Option<PeerId> {
+        let Protocol::Connect(connect) = *command else {
+            return None;
+        };
+
+        let address = self.received_address.clone()?;
+        let channel_count = connect.channel_count as usize;
+        if channel_count < MINIMUM_CHANNEL_COUNT as usize || channel_count > MAXIMUM_CHANNEL_COUNT as usize {
+            return None;
+        }
+
+        let mut free_peer = None;
+        let mut duplicate_peers = 0;
+        for (id, peer) in self.peers.iter().enumerate() {
+            if peer.state == PeerState::Disconnected {
+                free_peer.get_or_insert(id);
+            } else if peer.state != PeerState::Connecting && peer.address.same_host(&address) {
+                if peer.address == address && peer.connect_id == connect.connect_id {
+                    // a retransmitted connect for a connection already in progress
+                    return None;
+                }
+
+                duplicate_peers += 1;
+            }
+        }
+
+        let id = free_peer?;
+        if duplicate_peers >= self.duplicate_peers {
+            return None;
+        }
+
+        self.setup_channels(id, channel_count);
+        self.change_state(id, PeerState::AcknowledgingConnect);
+
+        let peer = &mut self.peers[id];
+        peer.connect_id = connect.connect_id;
+        peer.address = address;
+        peer.mtu = self.mtu.min(connect.mtu.clamp(MINIMUM_MTU, MAXIMUM_MTU));
+        peer.negotiated_mtu = peer.mtu;
+        peer.outgoing_peer_id = connect.outgoing_peer_id;
+        peer.incoming_bandwidth = connect.incoming_bandwidth;
+        peer.outgoing_bandwidth = connect.outgoing_bandwidth;
+        peer.window_size = window_size(self.outgoing_bandwidth, peer.incoming_bandwidth);
+        peer.packet_throttle_interval = connect.packet_throttle_interval;
+        peer.packet_throttle_accel = connect.packet_throttle_acceleration.min(PEER_PACKET_THROTTLE_SCALE);
+        peer.packet_throttle_decel = connect.packet_throttle_deceleration.min(PEER_PACKET_THROTTLE_SCALE);
+        peer.event_data = connect.data;
+
+        let session_mask = (HEADER_SESSION_MASK >> HEADER_SESSION_SHIFT) as u8;
+
+        let incoming_session_id = if connect.incoming_session_id == 0xFF { peer.outgoing_session_id } else { connect.incoming_session_id };
+        let mut incoming_session_id = incoming_session_id.wrapping_add(1) & session_mask;
+        if incoming_session_id == peer.outgoing_session_id {
+            incoming_session_id = incoming_session_id.wrapping_add(1) & session_mask;
+        }
+        peer.outgoing_session_id = incoming_session_id;
+
+        let outgoing_session_id = if connect.outgoing_session_id == 0xFF { peer.incoming_session_id } else { connect.outgoing_session_id };
+        let mut outgoing_session_id = outgoing_session_id.wrapping_add(1) & session_mask;
+        if outgoing_session_id == peer.incoming_session_id {
+            outgoing_session_id = outgoing_session_id.wrapping_add(1) & session_mask;
+        }
+        peer.incoming_session_id = outgoing_session_id;
+
+        let command = Protocol::VerifyConnect(ProtocolVerifyConnect {
+            header: ProtocolCommandHeader { command: ProtocolCommand::VerifyConnect as u8 | COMMAND_FLAG_ACKNOWLEDGE, channel_id: 0xFF, reliable_sequence_number: 0 },
+            outgoing_peer_id: peer.incoming_peer_id,
+            incoming_session_id,
+            outgoing_session_id,
+            mtu: peer.mtu,
+            window_size: window_size(self.incoming_bandwidth, 0).min(connect.window_size).clamp(MINIMUM_WINDOW_SIZE, MAXIMUM_WINDOW_SIZE),
+            channel_count: peer.channel_count as u32,
+            incoming_bandwidth: self.incoming_bandwidth,
+            outgoing_bandwidth: self.outgoing_bandwidth,
+            packet_throttle_interval: peer.packet_throttle_interval,
+            packet_throttle_acceleration: peer.packet_throttle_accel,
+            packet_throttle_deceleration: peer.packet_throttle_decel,
+            connect_id: peer.connect_id,
+        });
+
+        peer.queue_outgoing_command(command, None, 0, 0);
+        Some(id)
+    }
+
+    fn handle_verify_connect(&mut self, id: PeerId, command: &Protocol) -> HandleResult {
+        let Protocol::VerifyConnect(verify) = *command else {
+            return Err(CommandError);
+        };
+
+        let peer = &mut self.peers[id];
+        if peer.state != PeerState::Connecting {
+            return Ok(());
+        }
+
+        let channel_count = verify.channel_count as usize;
+        if channel_count < MINIMUM_CHANNEL_COUNT as usize || channel_count > MAXIMUM_CHANNEL_COUNT as usize ||
+           verify.packet_throttle_interval != peer.packet_throttle_interval ||
+           verify.packet_throttle_acceleration != peer.packet_throttle_accel ||
+           verify.packet_throttle_deceleration != peer.packet_throttle_decel ||
+           verify.connect_id != peer.connect_id {
+            peer.event_data = 0;
+            self.dispatch_state(id, PeerState::Zombie);
+            return Err(CommandError);
+        }
+
+        peer.remove_sent_reliable_command(1, 0xFF);
+
+        if channel_count < peer.channel_count {
+            peer.channels.truncate(channel_count);
+            peer.channel_count = channel_count;
+        }
+
+        peer.mtu = peer.mtu.min(verify.mtu.clamp(MINIMUM_MTU, MAXIMUM_MTU));
+        peer.negotiated_mtu = peer.mtu;
+        peer.window_size = peer.window_size.min(verify.window_size.clamp(MINIMUM_WINDOW_SIZE, MAXIMUM_WINDOW_SIZE));
+        peer.outgoing_peer_id = verify.outgoing_peer_id;
+        peer.incoming_session_id = verify.incoming_session_id;
+        peer.outgoing_session_id = verify.outgoing_session_id;
+        peer.incoming_bandwidth = verify.incoming_bandwidth;
+        peer.outgoing_bandwidth = verify.outgoing_bandwidth;
+
+        self.notify_connect(id);
+        Ok(())
+    }
+
+    fn handle_disconnect(&mut self, id: PeerId, command: &Protocol) -> HandleResult {
+        let Protocol::Disconnect(disconnect) = *command else {
+            return Err(CommandError);
+        };
+
+        let state = self.peers[id].state;
+        if state == PeerState::Disconnected || state == PeerState::Zombie || state == PeerState::AcknowledgingDisconnect {
+            return Ok(());
+        }
+
+        self.reset_peer_queues(id);
+
+        if state == PeerState::ConnectionSucceeded || state == PeerState::Disconnecting || state == PeerState::Connecting {
+            self.dispatch_state(id, PeerState::Zombie);
+        } else if !state.is_connected() {
+            if state == PeerState::ConnectionPending {
+                self.recalculate_bandwidth_limits = true;
+            }
+            self.reset_peer(id);
+        } else if disconnect.header.command & COMMAND_FLAG_ACKNOWLEDGE != 0 {
+            self.change_state(id, PeerState::AcknowledgingDisconnect);
+        } else {
+            self.dispatch_state(id, PeerState::Zombie);
+        }
+
+        let peer = &mut self.peers[id];
+        if peer.state != PeerState::Disconnected {
+            peer.event_data = disconnect.data;
+        }
+
+        Ok(())
+    }
+
+    fn handle_ping(&mut self, id: PeerId) -> HandleResult {
+        if !self.peers[id].state.is_connected() {
+            return Err(CommandError);
+        }
+
+        Ok(())
+    }
+
+    fn handle_bandwidth_limit(&mut self, id: PeerId, command: &Protocol) -> HandleResult {
+        let Protocol::BandwidthLimit(limit) = *command else {
+            return Err(CommandError);
+        };
+
+        let peer = &mut self.peers[id];
+        if !peer.state.is_connected() {
+            return Err(CommandError);
+        }
+
+        if peer.incoming_bandwidth != 0 {
+            self.bandwidth_limited_peers -= 1;
+        }
+
+        peer.incoming_bandwidth = limit.incoming_bandwidth;
+        peer.outgoing_bandwidth = limit.outgoing_bandwidth;
+
+        if peer.incoming_bandwidth != 0 {
+            self.bandwidth_limited_peers += 1;
+        }
+
+        peer.window_size = window_size(self.outgoing_bandwidth, peer.incoming_bandwidth);
+
+        Ok(())
+    }
+
+    fn handle_throttle_configure(&mut self, id: PeerId, command: &Protocol) -> HandleResult {
+        let Protocol::ThrottleConfigure(throttle) = *command else {
+            return Err(CommandError);
+        };
+
+        let peer = &mut self.peers[id];
+        if !peer.state.is_connected() {
+            return Err(CommandError);
+        }
+
+        peer.packet_throttle_interval = throttle.packet_throttle_interval;
+        peer.packet_throttle_accel = throttle.packet_throttle_acceleration.min(PEER_PACKET_THROTTLE_SCALE);
+        peer.packet_throttle_decel = throttle.packet_throttle_deceleration.min(PEER_PACKET_THROTTLE_SCALE);
+        Ok(())
+    }
+
+    fn handle_send(&mut self, id: PeerId, command: &Protocol, payload: &[u8], flags: u32) -> HandleResult {
+        let peer = &mut self.peers[id];
+        if command.header().channel_id as usize >= peer.channel_count || !peer.state.is_connected() ||
+           payload.len() > self.maximum_packet_size {
+            return Err(CommandError);
+        }
+
+        peer.queue_incoming_command(command, payload.to_vec(), flags, 0, self.maximum_waiting_data).map_err(|_| CommandError)?;
+        self.queue_dispatch(id);
+        Ok(())
+    }
+
+    fn handle_send_fragment(&mut self, id: PeerId, command: &Protocol, payload: &[u8]) -> HandleResult {
+        let peer = &mut self.peers[id];
+        if command.header().channel_id as usize >= peer.channel_count || !peer.state.is_connected() ||
+           payload.is_empty() || payload.len() > self.maximum_packet_size {
+            return Err(CommandError);
+        }
+
+        peer.queue_incoming_fragment(command, payload, self.maximum_packet_size, self.maximum_waiting_data).map_err(|_| CommandError)?;
+        self.queue_dispatch(id);
+        Ok(())
+    }
+
+    fn handle_send_unsequenced(&mut self, id: PeerId, command: &Protocol, payload: &[u8]) -> HandleResult {
+        let Protocol::SendUnsequenced(unsequenced) = *command else {
+            return Err(CommandError);
+        };
+
+        let peer = &mut self.peers[id];
+        if unsequenced.header.channel_id as usize >= peer.channel_count || !peer.state.is_connected() ||
+           payload.len() > self.maximum_packet_size {
+            return Err(CommandError);
+        }
+
+        let mut unsequenced_group = unsequenced.unsequenced_group as u32;
+        let index = unsequenced_group % PEER_UNSEQUENCED_WINDOW_SIZE;
+
+        if unsequenced_group < peer.incoming_unsequenced_group as u32 {
+            unsequenced_group += 0x10000;
+        }
+
+        if unsequenced_group >= peer.incoming_unsequenced_group as u32 + PEER_FREE_UNSEQUENCED_WINDOWS * PEER_UNSEQUENCED_WINDOW_SIZE {
+            return Ok(());
+        }
+
+        unsequenced_group &= 0xFFFF;
+
+        // moving to a newer window forgets everything seen in the old one
+        if unsequenced_group - index != peer.incoming_unsequenced_group as u32 {
+            peer.incoming_unsequenced_group = (unsequenced_group - index) as u16;
+            peer.unsequenced_window.fill(0);
+        } else if peer.unsequenced_window[(index / 32) as usize] & (1 << (index % 32)) != 0 {
+            return Ok(());
+        }
+
+        peer.queue_incoming_command(command, payload.to_vec(), PACKET_FLAG_UNSEQUENCED, 0, self.maximum_waiting_data).map_err(|_| CommandError)?;
+        peer.unsequenced_window[(index / 32) as usize] |= 1 << (index % 32);
+
+        self.queue_dispatch(id);
+        Ok(())
+    }
+
+    fn handle_acknowledge(&mut self, id: PeerId, command: &Protocol) -> HandleResult {
+        let Protocol::Acknowledge(acknowledge) = *command else {
+            return Err(CommandError);
+        };
+
+        let peer = &mut self.peers[id];
+        if peer.state == PeerState::Disconnected || peer.state == PeerState::Zombie {
+            return Ok(());
+        }
+
+        // only the low 16 bits of the sent time travel on the wire
+        let mut received_sent_time = acknowledge.received_sent_time as u32 | (self.service_time & 0xFFFF0000);
+        if (received_sent_time & 0x8000) > (self.service_time & 0x8000) {
+            received_sent_time = received_sent_time.wrapping_sub(0x10000);
+        }
+
+        if time_less(self.service_time, received_sent_time) {
+            return Ok(());
+        }
+
+        peer.update_roundtrip_time(time_difference(self.service_time, received_sent_time), self.service_time);
+
+        let command_number = peer.remove_sent_reliable_command(acknowledge.received_reliable_sequence_number, acknowledge.header.channel_id);
+        if peer.is_mtu_probe(acknowledge.header.channel_id, acknowledge.received_reliable_sequence_number) {
+            peer.mtu_probe_acknowledged();
+        }
+
+        match peer.state {
+            PeerState::AcknowledgingConnect => {
+                if command_number != ProtocolCommand::VerifyConnect {
+                    return Err(CommandError);
+                }
+
+                self.notify_connect(id);
+            }
+
+            PeerState::Disconnecting => {
+                if command_number != ProtocolCommand::Disconnect {
+                    return Err(CommandError);
+                }
+
+                self.notify_disconnect(id);
+            }
+
+            PeerState::DisconnectLater if !peer.has_outgoing_commands() => {
+                let data = peer.event_data;
+                let _ = self.disconnect(id, data);
+            }
+
+            _ => {}
+        }
+
+        Ok(())
+    }
+
+    fn notify_connect(&mut self, id: PeerId) {
+        self.recalculate_bandwidth_limits = true;
+
+        let state = if self.peers[id].state == PeerState::Connecting {
+            PeerState::ConnectionSucceeded
+        } else {
+            PeerState::ConnectionPending
+        };
+
+        self.dispatch_state(id, state);
+    }
+
+    fn notify_disconnect(&mut self, id: PeerId) {
+        let state = self.peers[id].state;
+        if state >= PeerState::ConnectionPending {
+            self.recalculate_bandwidth_limits = true;
+        }
+
+        if state != PeerState::Connecting && state < PeerState::ConnectionSucceeded {
+            self.reset_peer(id);
+        } else {
+            self.peers[id].event_data = 0;
+            self.dispatch_state(id, PeerState::Zombie);
+        }
+    }
+
+    /// Like `notify_disconnect`, but reports the peer as timed out
+    fn notify_disconnect_timeout(&mut self, id: PeerId) {
+        let state = self.peers[id].state;
+        if state == PeerState::Connecting || state >= PeerState::ConnectionSucceeded {
+            self.peers[id].flags |= PEER_FLAG_TIMED_OUT;
+        }
+
+        self.notify_disconnect(id);
+    }
+
+    /// Assembles and sends a datagram for every peer with pending commands.
+    ///
+    /// Peers whose commands did not fit are revisited in further passes.
+    fn send_outgoing_commands(&mut self, check_for_timeouts: bool) {
+        let mut send_pass = 0;
+        let mut continue_sending = 0;
+
+        while send_pass <= continue_sending {
+            for id in 0..self.peers.len() {
+                let peer = &mut self.peers[id];
+                if peer.state == PeerState::Disconnected || peer.state == PeerState::Zombie ||
+                   (send_pass > 0 && peer.flags & PEER_FLAG_CONTINUE_SENDING == 0) {
+                    continue;
+                }
+
+                peer.flags &= !PEER_FLAG_CONTINUE_SENDING;
+
+                self.header_flags = 0;
+                self.command_count = 0;
+                self.packet_size = mem::size_of::<ProtocolHeader>();
+                self.packet_data.clear();
+
+                if !self.peers[id].acknowledgements.is_empty() {
+                    self.send_acknowledgements(id);
+                }
+
+                let peer = &mut self.peers[id];
+                if check_for_timeouts && !peer.sent_reliable_commands.is_empty() &&
+                   time_greater_equal(self.service_time, peer.next_timeout) && peer.check_timeouts(self.service_time) {
+                    self.notify_disconnect_timeout(id);
+                    continue;
+                }
+
+                if self.mtu_probing && let Some(size) = self.peers[id].next_mtu_probe(self.service_time) {
+                    self.queue_mtu_probe(id, size);
+                }
+
+                let peer = &self.peers[id];
+                let can_ping = if !peer.outgoing_commands.is_empty() || !peer.outgoing_send_reliable_commands.is_empty() {
+                    self.check_outgoing_commands(id)
+                } else {
+                    true
+                };
+
+                // keep idle connections alive and their round trip time fresh
+                let peer = &mut self.peers[id];
+                if can_ping && peer.state == PeerState::Connected && peer.flags & PEER_FLAG_NO_PINGS == 0 && peer.sent_reliable_commands.is_empty() &&
+                   time_difference(self.service_time, peer.last_receive_time) >= peer.ping_interval &&
+                   (peer.mtu as usize).saturating_sub(self.packet_size) >= command_size(ProtocolCommand::Ping as u8) {
+                    peer.ping();
+                    self.check_outgoing_commands(id);
+                }
+
+                if self.command_count > 0 {
+                    self.peers[id].update_packet_loss(self.service_time);
+                    self.send_datagram(id);
+                }
+
+                if self.peers[id].flags & PEER_FLAG_CONTINUE_SENDING != 0 {
+                    continue_sending = send_pass + 1;
+                }
+            }
+
+            send_pass += 1;
+        }
+    }
+
+    /// Pads a probe to `size` bytes on the wire. The padding starts with a
+    /// zero byte, which receivers take as the end of the datagram's commands,
+    /// and is random otherwise so compression cannot shrink it.
+    fn queue_mtu_probe(&mut self, id: PeerId, size: u32) {
+        let checksum_size = if self.checksum.is_some() { mem::size_of::<u32>() } else { 0 };
+        let overhead = mem::size_of::<ProtocolHeader>() + checksum_size + command_size(ProtocolCommand::Ping as u8);
+
+        let mut padding: Vec<u8> = (overhead..size as usize).map(|_| self.random() as u8).collect();
+        padding[0] = 0;
+
+        self.peers[id].queue_mtu_probe(size, Packet::from_vec(padding, 0));
+    }
+
+    /// Writes queued acknowledgements ahead of any other command, leaving
+    /// whatever does not fit for the next datagram
+    fn send_acknowledgements(&mut self, id: PeerId) {
+        let acknowledge_size = command_size(ProtocolCommand::Acknowledge as u8);
+        let mut sent = 0;
+
+        while sent < self.peers[id].acknowledgements.len() {
+            let peer = &mut self.peers[id];
+            if self.command_count >= MAXIMUM_PACKET_COMMANDS as usize || (peer.mtu as usize) < self.packet_size + acknowledge_size {
+                peer.flags |= PEER_FLAG_CONTINUE_SENDING;
+                break;
+            }
+
+            let acknowledgement = peer.acknowledgements[sent];
+            let header = *acknowledgement.command.header();
+            let reliable_sequence_number = header.reliable_sequence_number;
+            sent += 1;
+
+            Protocol::Acknowledge(ProtocolAcknowledge {
+                header: ProtocolCommandHeader { command: ProtocolCommand::Acknowledge as u8, channel_id: header.channel_id, reliable_sequence_number },
+                received_reliable_sequence_number: reliable_sequence_number,
+                received_sent_time: acknowledgement.sent_time as u16,
+            }).encode(&mut self.packet_data);
+
+            self.packet_size += acknowledge_size;
+            self.command_count += 1;
+
+            if acknowledgement.command.command() == Some(ProtocolCommand::Disconnect) {
+                self.dispatch_state(id, PeerState::Zombie);
+            }
+        }
+
+        self.peers[id].acknowledgements.drain(..sent);
+    }
+
+    /// Moves queued commands into the datagram being assembled, returning
+    /// whether none of them asks for an acknowledgement
+    fn check_outgoing_commands(&mut self, id: PeerId) -> bool {
+        let peer = &mut self.peers[id];
+        let mut can_ping = true;
+
+        let mut command_index = 0;
+        let mut reliable_index = 0;
+        let mut window_wrap = false;
+
+        loop {
+            // interleave both queues in the order the commands were queued
+            let from_reliable_queue = if command_index < peer.outgoing_commands.len() {
+                reliable_index < peer.outgoing_send_reliable_commands.len() &&
+                time_less(peer.outgoing_send_reliable_commands[reliable_index].queue_time, peer.outgoing_commands[command_index].queue_time)
+            } else if reliable_index < peer.outgoing_send_reliable_commands.len() {
+                true
+            } else {
+                break;
+            };
+
+            let outgoing_command = if from_reliable_queue {
+                &peer.outgoing_send_reliable_commands[reliable_index]
+            } else {
+                &peer.outgoing_commands[command_index]
+            };
+
+            let header = *outgoing_command.command.header();
+            let reliable_window = (outgoing_command.reliable_seq_num / PEER_RELIABLE_WINDOW_SIZE as u16) as usize;
+            let channel = peer.channels.get(header.channel_id as usize);
+
+            if header.command & COMMAND_FLAG_ACKNOWLEDGE != 0 {
+                if let Some(channel) = channel {
+                    // a command opening a new reliable window waits until the
+                    // receiver can no longer confuse it with an older one
+                    let free_windows = (1u32 << (PEER_FREE_RELIABLE_WINDOWS + 2)) - 1;
+                    let window_in_use = channel.used_reliable_windows as u32 &
+                        ((free_windows << reliable_window) | (free_windows >> (PEER_RELIABLE_WINDOWS as usize - reliable_window))) != 0;
+
+                    if !window_wrap && outgoing_command.send_attempts < 1 && outgoing_command.reliable_seq_num % PEER_RELIABLE_WINDOW_SIZE as u16 == 0 &&
+                       (channel.reliable_windows[(reliable_window + PEER_RELIABLE_WINDOWS as usize - 1) % PEER_RELIABLE_WINDOWS as usize] >= PEER_RELIABLE_WINDOW_SIZE as u16 || window_in_use) {
+                        window_wrap = true;
+                        reliable_index = peer.outgoing_send_reliable_commands.len();
+                    }
+
+                    if window_wrap {
+                        if !from_reliable_queue {
+                            command_index += 1;
+                        }
+                        continue;
+                    }
+                }
+
+                // reliable payloads stop once the throttled window is full
+                if outgoing_command.packet.is_some() && !peer.is_mtu_probe(header.channel_id, outgoing_command.reliable_seq_num) {
+                    let window_size = peer.packet_throttle * peer.window_size / PEER_PACKET_THROTTLE_SCALE;
+                    if peer.reliable_data_in_transit + outgoing_command.fragment_length > window_size.max(peer.mtu) {
+                        reliable_index = peer.outgoing_send_reliable_commands.len();
+                        continue;
+                    }
+                }
+
+                // the acknowledgement for this command doubles as a ping
+                can_ping = false;
+            }
+
+            // a probe may exceed the MTU, but only by being alone in its datagram
+            let mtu = if peer.is_mtu_probe(header.channel_id, outgoing_command.reliable_seq_num) { peer.mtu_probe } else { peer.mtu };
+            let command_size = command_size(header.command);
+            if self.command_count >= MAXIMUM_PACKET_COMMANDS as usize ||
+               (mtu as usize) < self.packet_size + command_size + outgoing_command.fragment_length as usize {
+                peer.flags |= PEER_FLAG_CONTINUE_SENDING;
+                break;
+            }
+
+            if header.command & COMMAND_FLAG_ACKNOWLEDGE != 0 && outgoing_command.send_attempts < 1 &&
+               let Some(channel) = peer.channels.get_mut(header.channel_id as usize) {
+                channel.used_reliable_windows |= 1 << reliable_window;
+                channel.reliable_windows[reliable_window] += 1;
+            }
+
+            if header.command & COMMAND_FLAG_ACKNOWLEDGE == 0 && outgoing_command.packet.is_some() && outgoing_command.fragment_offset == 0 {
+                let reliable_seq_num = outgoing_command.reliable_seq_num;
+                let unreliable_seq_num = outgoing_command.unreliable_seq_num;
+
+                peer.packet_throttle_counter = (peer.packet_throttle_counter + PEER_PACKET_THROTTLE_COUNTER) % PEER_PACKET_THROTTLE_SCALE;
+                if peer.packet_throttle_counter > peer.packet_throttle {
+                    // the remaining fragments of a throttled packet are dropped with it
+                    peer.outgoing_commands.remove(command_index);
+                    while peer.outgoing_commands.get(command_index).is_some_and(|next|
+                        next.reliable_seq_num == reliable_seq_num && next.unreliable_seq_num == unreliable_seq_num) {
+                        peer.outgoing_commands.remove(command_index);
+                    }
+                    continue;
+                }
+            }
+
+            let mut outgoing_command = if from_reliable_queue {
+                peer.outgoing_send_reliable_commands.remove(reliable_index)
+            } else {
+                peer.outgoing_commands.remove(command_index)
+            };
+
+            outgoing_command.command.encode(&mut self.packet_data);
+            if let Some(packet) = &outgoing_command.packet {
+                let offset = outgoing_command.fragment_offset as usize;
+                self.packet_data.extend_from_slice(&packet.data[offset..offset + outgoing_command.fragment_length as usize]);
+            }
+
+            self.packet_size += command_size + outgoing_command.fragment_length as usize;
+            self.command_count += 1;
+            peer.packets_sent = peer.packets_sent.wrapping_add(1);
+
+            if outgoing_command.command.header().command & COMMAND_FLAG_ACKNOWLEDGE != 0 {
+                outgoing_command.send_attempts += 1;
+
+                // retransmissions keep their backed off timeout
+                if outgoing_command.roundtrip_timeout == 0 {
+                    outgoing_command.roundtrip_timeout = peer.roundtrip_time + 4 * peer.roundtrip_time_variance;
+                }
+
+                if peer.sent_reliable_commands.is_empty() {
+                    peer.next_timeout = self.service_time.wrapping_add(outgoing_command.roundtrip_timeout);
+                }
+
+                outgoing_command.sent_time = self.service_time;
+                self.header_flags |= HEADER_FLAG_SENT_TIME;
+                peer.reliable_data_in_transit += outgoing_command.fragment_length;
+
+                peer.sent_reliable_commands.push(outgoing_command);
+            } else if let Some(mut packet) = outgoing_command.packet.take() {
+                packet.mark_sent();
+            }
+        }
+
+        can_ping
+    }
+
+    /// Prefixes the assembled commands with a header and queues the datagram
+    fn send_datagram(&mut self, id: PeerId) {
+        let peer = &mut self.peers[id];
+
+        let mut header_flags = self.header_flags;
+        if peer.outgoing_peer_id < MAXIMUM_PEER_ID as u16 {
+            header_flags |= (peer.outgoing_session_id as u16) << HEADER_SESSION_SHIFT;
+        }
+
+        // only worth flagging when it actually saves space
+        let mut compressed = None;
+        if let Some(compressor) = &mut self.compressor {
+            let original_size = self.packet_data.len();
+            let mut out = vec![0; original_size];
+            if let Some(compressed_size) = compressor.compress(&[&self.packet_data], original_size, &mut out) &&
+               compressed_size > 0 && compressed_size < original_size {
+                header_flags |= HEADER_FLAG_COMPRESSED;
+                out.truncate(compressed_size);
+                compressed = Some(out);
+            }
+        }
+
+        let header = ProtocolHeader {
+            peer_id: peer.outgoing_peer_id | header_flags,
+            sent_time: self.service_time as u16,
+        };
+
+        let mut datagram = Vec::with_capacity(self.packet_size + mem::size_of::<u32>());
+        header.encode(&mut datagram);
+
+        if let Some(checksum) = self.checksum {
+            let seed = if peer.outgoing_peer_id < MAXIMUM_PEER_ID as u16 { peer.connect_id } else { 0 };
+            let checksum_offset = datagram.len();
+            datagram.extend_from_slice(&seed.to_be_bytes());
+
+            let checksum = checksum(&[&datagram, &self.packet_data]);
+            datagram[checksum_offset..].copy_from_slice(&checksum.to_be_bytes());
+        }
+        datagram.extend_from_slice(compressed.as_deref().unwrap_or(&self.packet_data));
+
+        peer.last_send_time = self.service_time;
+
+        self.total_sent_data = self.total_sent_data.wrapping_add(datagram.len() as u32);
+        self.total_sent_packets = self.total_sent_packets.wrapping_add(1);
+        self.transmits.push_back(Transmit { address: peer.address.clone(), data: datagram });
+    }
+
+    fn dispatch_incoming_commands(&mut self) -> Option<Event> {
+        while let Some(id) = self.dispatch_queue.pop_front() {
+            let peer = &mut self.peers[id];
+            peer.flags &= !PEER_FLAG_NEEDS_DISPATCH;
+
+            match peer.state {
+                PeerState::ConnectionPending | PeerState::ConnectionSucceeded => {
+                    self.change_state(id, PeerState::Connected);
+
+                    return Some(Event {
+                        event_type: EventType::Connect,
+                        peer: id,
+                        channel_id: 0,
+                        data: self.peers[id].event_data,
+                        packet: None,
+                    });
+                }
+
+                PeerState::Zombie => {
+                    self.recalculate_bandwidth_limits = true;
+
+                    let data = peer.event_data;
+                    let event_type = if peer.flags & PEER_FLAG_TIMED_OUT != 0 { EventType::DisconnectTimeout } else { EventType::Disconnect };
+                    self.reset_peer(id);
+
+                    return Some(Event {
+                        event_type,
+                        peer: id,
+                        channel_id: 0,
+                        data,
+                        packet: None,
+                    });
+                }
+
+                PeerState::Connected => {
+                    let Some((channel_id, packet)) = peer.receive() else {
+                        continue;
+                    };
+
+                    self.queue_dispatch(id);
+
+                    return Some(Event {
+                        event_type: EventType::Receive,
+                        peer: id,
+                        channel_id,
+                        data: 0,
+                        packet: Some(packet),
+                    });
+                }
+
+                _ => {}
+            }
+        }
+
+        None
+    }
+
+    /// Moves a peer into `state`, keeping the host's connection counters in sync.
+    /// Panics on a transition the protocol never makes.
+    fn change_state(&mut self, id: PeerId, state: PeerState) {
+        assert!(self.peers[id].state.can_transition_to(state), "illegal peer transition from {:?} to {:?}", self.peers[id].state, state);
+
+        if state.is_connected() {
+            self.on_peer_connect(id);
+        } else {
+            self.on_peer_disconnect(id);
+        }
+
+        self.peers[id].state = state;
+    }
+
+    /// Changes a peer's state and queues it so the transition surfaces as an event
+    fn dispatch_state(&mut self, id: PeerId, state: PeerState) {
+        self.change_state(id, state);
+
+        let peer = &mut self.peers[id];
+        if peer.flags & PEER_FLAG_NEEDS_DISPATCH == 0 {
+            peer.flags |= PEER_FLAG_NEEDS_DISPATCH;
+            self.dispatch_queue.push_back(id);
+        }
+    }
+
+    /// Queues a peer for dispatch if it has packets ready for the application
+    fn queue_dispatch(&mut self, id: PeerId) {
+        let peer = &mut self.peers[id];
+        if peer.flags & PEER_FLAG_NEEDS_DISPATCH == 0 && !peer.dispatched_commands.is_empty() {
+            peer.flags |= PEER_FLAG_NEEDS_DISPATCH;
+            self.dispatch_queue.push_back(id);
+        }
+    }
+
+    fn on_peer_connect(&mut self, id: PeerId) {
+        let peer = &self.peers[id];
+        if !peer.state.is_connected() {
+            if peer.incoming_bandwidth != 0 {
+                self.bandwidth_limited_peers += 1;
+            }
+            self.connected_peers += 1;
+        }
+    }
+
+    fn on_peer_disconnect(&mut self, id: PeerId) {
+        let peer = &self.peers[id];
+        if peer.state.is_connected() {
+            if peer.incoming_bandwidth != 0 {
+                self.bandwidth_limited_peers -= 1;
+            }
+            self.connected_peers -= 1;
+        }
+    }
+
+    /// Forcefully drops a peer without notifying the remote side
+    fn reset_peer(&mut self, id: PeerId) {
+        self.on_peer_disconnect(id);
+        self.reset_peer_queues(id);
+
+        let peer = &mut self.peers[id];
+        peer.reset();
+        peer.mtu = self.mtu;
+        peer.negotiated_mtu = self.mtu;
+    }
+
+    fn reset_peer_queues(&mut self, id: PeerId) {
+        if self.peers[id].flags & PEER_FLAG_NEEDS_DISPATCH != 0 {
+            self.dispatch_queue.retain(|&queued| queued != id);
+        }
+
+        self.peers[id].reset_queues();
+    }
+
+    /// Allocates the channels a freshly connecting peer will use
+    fn setup_channels(&mut self, id: PeerId, channel_count: usize) {
+        let channel_count = channel_count.clamp(MINIMUM_CHANNEL_COUNT as usize, self.channel_limit);
+
+        let peer = &mut self.peers[id];
+        peer.channels = (0..channel_count).map(|_| Channel::default()).collect();
+        peer.channel_count = channel_count;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::{Arc, Mutex};
+    use crate::packet::{crc32, PacketRelease};
+    use crate::time::MockClock;
+    use crate::simulator::{LinkConditions, Simulator};
+    use std::sync::mpsc::{channel, Receiver, Sender};
+
+    fn localhost() -> Option<SocketAddr> {
+        Some(SocketAddr::from((Ipv4Addr::LOCALHOST, 0)))
+    }
+
+    /// Services both hosts until `host` reports an event
+    fn pump<S: Socket>(host: &mut Host<S>, other: &mut Host<S>) -> Event {
+        for _ in 0..200 {
+            if let Some(event) = host.service(5).unwrap() {
+                return event;
+            }
+            assert!(other.service(5).unwrap().is_none(), "unexpected event on the other host");
+        }
+
+        panic!("no event was generated");
+    }
+
+    #[test]
+    fn create_host() {
+        let host = Host::create(localhost(), 8, 0, 0, 0).unwrap();
+
+        assert_eq!(host.peers.len(), 8);
+        assert_eq!(host.channel_limit, MAXIMUM_CHANNEL_COUNT as usize);
+        assert!(host.peers.iter().all(|peer| peer.state == PeerState::Disconnected));
+        assert_eq!(host.peers[3].incoming_peer_id, 3);
+    }
+
+    #[test]
+    fn create_host_rejects_too_many_peers() {
+        assert!(Host::create(None, MAXIMUM_PEER_ID as usize + 1, 0, 0, 0).is_err());
+    }
+
+    #[test]
+    fn service_times_out_without_traffic() {
+        let mut host = Host::create(localhost(), 1, 1, 0, 0).unwrap();
+
+        assert!(host.service(10).unwrap().is_none());
+    }
+
+    #[test]
+    fn dispatch_connect_and_disconnect() {
+        let mut host = Host::create(localhost(), 2, 1, 0, 0).unwrap();
+
+        host.setup_channels(1, 1);
+        host.peers[1].event_data = 7;
+        host.change_state(1, PeerState::Connecting);
+        host.dispatch_state(1, PeerState::ConnectionSucceeded);
+
+        let event = host.check_events().unwrap();
+        assert_eq!(event.event_type, EventType::Connect);
+        assert_eq!(event.peer, 1);
+        assert_eq!(event.data, 7);
+        assert_eq!(host.connected_peers, 1);
+
+        host.dispatch_state(1, PeerState::Zombie);
+        let event = host.check_events().unwrap();
+        assert_eq!(event.event_type, EventType::Disconnect);
+        assert_eq!(host.connected_peers, 0);
+        assert_eq!(host.peers[1].state, PeerState::Disconnected);
+    }
+
+    #[test]
+    #[should_panic(expected = "illegal peer transition")]
+    fn illegal_transitions_are_rejected() {
+        let mut host = Host::create(localhost(), 1, 1, 0, 0).unwrap();
+        host.change_state(0, PeerState::Connected);
+    }
+
+    #[test]
+    fn connect_and_disconnect() {
+        let mut server = Host::create(localhost(), 4, 2, 0, 0).unwrap();
+        let mut client = Host::create(localhost(), 1, 2, 0, 0).unwrap();
+
+        let peer = client.connect(server.address, 2, 42).unwrap();
+        assert_eq!(client.peer(peer).state, PeerState::Connecting);
+
+        let event = pump(&mut client, &mut server);
+        assert_eq!(event.event_type, EventType::Connect);
+        assert_eq!(event.peer, peer);
+
+        let event = pump(&mut server, &mut client);
+        assert_eq!(event.event_type, EventType::Connect);
+        assert_eq!(event.data, 42);
+        assert_eq!(server.peer(event.peer).address, client.address);
+        assert_eq!(server.connected_peers, 1);
+
+        client.disconnect(peer, 7).unwrap();
+        assert_eq!(client.disconnect(peer, 7), Err(PeerError::InvalidState(PeerState::Disconnecting)));
+
+        let event = pump(&mut server, &mut client);
+        assert_eq!(event.event_type, EventType::Disconnect);
+        assert_eq!(event.data, 7);
+        assert_eq!(server.connected_peers, 0);
+
+        let event = pump(&mut client, &mut server);
+        assert_eq!(event.event_type, EventType::Disconnect);
+        assert_eq!(client.peer(peer).state, PeerState::Disconnected);
+    }
+
+    #[test]
+    fn disconnect_now_skips_the_event() {
+        let mut server = Host::create(localhost(), 1, 1, 0, 0).unwrap();
+        let mut client = Host::create(localhost(), 1, 1, 0, 0).unwrap();
+
+        let peer = client.connect(server.address, 1, 0).unwrap();
+        pump(&mut client, &mut server);
+        pump(&mut server, &mut client);
+
+        client.disconnect_now(peer, 3).unwrap();
+        assert_eq!(client.peer(peer).state, PeerState::Disconnected);
+        assert_eq!(client.connected_peers, 0);
+
+        let event = pump(&mut server, &mut client);
+        assert_eq!(event.event_type, EventType::Disconnect);
+        assert_eq!(event.data, 3);
+    }
+
+    fn connected_pair(channel_count: usize) -> (Host, Host, PeerId) {
+        let mut server = Host::create(localhost(), 1, channel_count, 0, 0).unwrap();
+        let mut client = Host::create(localhost(), 1, channel_count, 0, 0).unwrap();
+
+        let peer = client.connect(server.address, channel_count, 0).unwrap();
+        pump(&mut client, &mut server);
+        pump(&mut server, &mut client);
+
+        (server, client, peer)
+    }
+
+    #[test]
+    fn mtu_and_window_size_are_negotiated() {
+        let mut server = Host::create(localhost(), 1, 1, 256 * 1024, 0).unwrap();
+        let mut client = Host::create(localhost(), 1, 1, 128 * 1024, 0).unwrap();
+        client.mtu = 1000;
+
+        let peer = client.connect(server.address, 1, 0).unwrap();
+        pump(&mut client, &mut server);
+        pump(&mut server, &mut client);
+
+        assert_eq!(server.peer(0).mtu, 1000);
+        assert_eq!(client.peer(peer).mtu, 1000);
+        assert_eq!(server.peer(0).window_size, 2 * MINIMUM_WINDOW_SIZE);
+        assert_eq!(client.peer(peer).window_size, 4 * MINIMUM_WINDOW_SIZE);
+    }
+
+    /// Connects host 1 of a fresh simulator to host 0 and returns the client's peer id
+    fn simulated_pair() -> (Simulator, PeerId) {
+        let mut simulator = Simulator::new(0);
+        simulator.add_host(1, 1, 0, 0).unwrap();
+        simulator.add_host(1, 1, 0, 0).unwrap();
+
+        let peer = simulator.connect(1, 0, 1, 0).unwrap();
+        for _ in 0..2 {
+            let (_, event) = simulator.next_event(1000).expect("hosts never connected");
+            assert_eq!(event.event_type, EventType::Connect);
+        }
+
+        (simulator, peer)
+    }
+
+    #[test]
+    fn idle_peers_are_pinged() {
+        let (mut simulator, peer) = simulated_pair();
+        simulator.host_mut(1).peer_mut(peer).ping_interval(20);
+        simulator.host_mut(1).peer_mut(peer).set_pinging(false);
+        simulator.host_mut(0).peer_mut(0).set_pinging(false);
+
+        simulator.run(50);
+        let sent = simulator.host(1).total_sent_packets;
+        simulator.run(100);
+        assert_eq!(simulator.host(1).total_sent_packets, sent);
+
+        // one ping per interval once pinging is back on
+        simulator.host_mut(1).peer_mut(peer).set_pinging(true);
+        simulator.run(100);
+        assert_eq!(simulator.host(1).total_sent_packets, sent + 5);
+    }
+
+    #[test]
+    fn mtu_probing_raises_the_mtu() {
+        let (mut server, mut client, peer) = connected_pair(1);
+        client.mtu_probing = true;
+        client.checksum = Some(crc32);
+        server.checksum = Some(crc32);
+
+        for _ in 0..100 {
+            if client.peer(peer).mtu + PEER_MTU_PROBE_PRECISION >= MAXIMUM_MTU {
+                break;
+            }
+            assert!(client.service(5).unwrap().is_none());
+            assert!(server.service(5).unwrap().is_none());
+        }
+
+        let mtu = client.peer(peer).mtu;
+        assert!(mtu + PEER_MTU_PROBE_PRECISION >= MAXIMUM_MTU && mtu <= MAXIMUM_MTU, "mtu {mtu}");
+
+        // packets are still cut for the negotiated MTU, but their fragments now share a datagram
+        let sent = client.total_sent_packets;
+        client.send(peer, 0, Packet::from_vec(vec![7; 3000], PACKET_FLAG_RELIABLE)).unwrap();
+        assert_eq!(client.peer(peer).outgoing_send_reliable_commands.len(), 3);
+        client.flush().unwrap();
+        assert_eq!(client.total_sent_packets, sent + 1);
+
+        let event = pump(&mut server, &mut client);
+        assert_eq!(*event.packet.unwrap().data, vec![7; 3000]);
+    }
+
+    #[test]
+    fn window_size_limits_reliable_data_in_transit() {
+        let (mut server, mut client, peer) = connected_pair(1);
+
+        // a fully throttled peer still gets one MTU worth of data in flight
+        client.peer_mut(peer).packet_throttle = 0;
+        for i in 0..3u8 {
+            client.send(peer, 0, Packet::from_vec(vec![i; 1000], PACKET_FLAG_RELIABLE)).unwrap();
+        }
+        client.flush().unwrap();
+
+        assert_eq!(client.peer(peer).sent_reliable_commands.len(), 1);
+        assert_eq!(client.peer(peer).outgoing_send_reliable_commands.len(), 2);
+
+        for i in 0..3u8 {
+            let event = pump(&mut server, &mut client);
+            assert_eq!(event.packet.unwrap().data[0], i);
+        }
+    }
+
+    #[test]
+    fn reliable_packets_arrive_in_order() {
+        let (mut server, mut client, peer) = connected_pair(2);
+
+        for i in 0..100u32 {
+            client.send(peer, (i % 2) as u8, Packet::from_vec(i.to_be_bytes().to_vec(), PACKET_FLAG_RELIABLE)).unwrap();
+        }
+
+        let mut received = [Vec::new(), Vec::new()];
+        while received.iter().map(Vec::len).sum::<usize>() < 100 {
+            let event = pump(&mut server, &mut client);
+            assert_eq!(event.event_type, EventType::Receive);
+
+            let packet = event.packet.unwrap();
+            assert_eq!(packet.flags & PACKET_FLAG_RELIABLE, PACKET_FLAG_RELIABLE);
+            received[event.channel_id as usize].push(u32::from_be_bytes(packet.data[..4].try_into().unwrap()));
+        }
+
+        assert_eq!(received[0], (0..100).step_by(2).collect::<Vec<_>>());
+        assert_eq!(received[1], (1..100).step_by(2).collect::<Vec<_>>());
+    }
+
+    #[test]
+    fn unreliable_and_unsequenced_packets_arrive() {
+        let (mut server, mut client, peer) = connected_pair(1);
+
+        for i in 0..10u32 {
+            let flags = if i % 2 == 0 { 0 } else { PACKET_FLAG_UNSEQUENCED };
+            client.send(peer, 0, Packet::from_vec(i.to_be_bytes().to_vec(), flags)).unwrap();
+        }
+
+        let mut received = Vec::new();
+        while received.len() < 10 {
+            let event = pump(&mut server, &mut client);
+            assert_eq!(event.event_type, EventType::Receive);
+
+            let packet = event.packet.unwrap();
+            let value = u32::from_be_bytes(packet.data[..4].try_into().unwrap());
+            assert_eq!(packet.flags & PACKET_FLAG_UNSEQUENCED, if value % 2 == 0 { 0 } else { PACKET_FLAG_UNSEQUENCED });
+            received.push(value);
+        }
+
+        received.sort();
+        assert_eq!(received, (0..10).collect::<Vec<_>>());
+    }
+
+    #[test]
+    fn throttled_peers_drop_unreliable_packets() {
+        let (mut server, mut client, peer) = connected_pair(1);
+        client.peer_mut(peer).packet_throttle = PEER_PACKET_THROTTLE_SCALE / 2;
+        client.peer_mut(peer).packet_throttle_counter = 0;
+
+        for i in 0..8u8 {
+            client.send(peer, 0, Packet::from_vec(vec![i], 0)).unwrap();
+        }
+        client.send(peer, 0, Packet::from_vec(vec![0xFF], PACKET_FLAG_RELIABLE)).unwrap();
+        client.flush().unwrap();
+
+        let mut received = Vec::new();
+        loop {
+            let event = pump(&mut server, &mut client);
+            let packet = event.packet.unwrap();
+            if packet.flags & PACKET_FLAG_RELIABLE != 0 {
+                break;
+            }
+            received.push(packet.data[0]);
+        }
+
+        // the counter advances by 7 per packet and only values up to the
+        // throttle let a packet through
+        assert_eq!(received, [0, 1, 4, 5]);
+    }
+
+    #[test]
+    fn throttle_configuration_is_applied_remotely() {
+        let (mut server, mut client, peer) = connected_pair(1);
+        client.peer_mut(peer).throttle_configure(1000, 4, 6);
+
+        for _ in 0..100 {
+            if server.peer(0).packet_throttle_interval == 1000 {
+                break;
+            }
+            assert!(client.service(5).unwrap().is_none());
+            assert!(server.service(5).unwrap().is_none());
+        }
+
+        let remote = server.peer(0);
+        assert_eq!(remote.packet_throttle_interval, 1000);
+        assert_eq!(remote.packet_throttle_accel, 4);
+        assert_eq!(remote.packet_throttle_decel, 6);
+    }
+
+    #[test]
+    fn remote_throttle_configuration_is_clamped() {
+        let (mut server, mut client, peer) = connected_pair(1);
+        client.peer_mut(peer).throttle_configure(1000, u32::MAX, u32::MAX);
+
+        for _ in 0..100 {
+            if server.peer(0).packet_throttle_interval == 1000 {
+                break;
+            }
+            assert!(client.service(5).unwrap().is_none());
+            assert!(server.service(5).unwrap().is_none());
+        }
+
+        let remote = server.peer(0);
+        assert_eq!(remote.packet_throttle_accel, PEER_PACKET_THROTTLE_SCALE);
+        assert_eq!(remote.packet_throttle_decel, PEER_PACKET_THROTTLE_SCALE);
+    }
+
+    #[test]
+    fn unsequenced_packets_are_delivered_once() {
+        let mut host = Host::create(localhost(), 1, 1, 0, 0).unwrap();
+        let address = host.address;
+        connect_locally(&mut host, 0, address);
+
+        let mut deliver = |unsequenced_group: u16| {
+            let command = Protocol::SendUnsequenced(ProtocolSendUnsequenced {
+                header: ProtocolCommandHeader { command: ProtocolCommand::SendUnsequenced as u8 | COMMAND_FLAG_UNSEQUENCED, channel_id: 0, reliable_sequence_number: 0 },
+                unsequenced_group,
+                data_length: 2,
+            });
+            assert!(host.handle_send_unsequenced(0, &command, &unsequenced_group.to_be_bytes()).is_ok());
+            std::iter::from_fn(|| host.peer_mut(0).receive()).count()
+        };
+
+        assert_eq!(deliver(1), 1);
+        assert_eq!(deliver(1), 0);
+        assert_eq!(deliver(0), 1);
+        assert_eq!(deliver(1023), 1);
+
+        // moving into the next window clears the history
+        assert_eq!(deliver(1025), 1);
+        assert_eq!(deliver(1025), 0);
+        assert_eq!(deliver(1), 0);
+
+        // groups too far ahead are ignored
+        assert_eq!(deliver(1024 + 32 * 1024), 0);
+        assert_eq!(host.peer(0).incoming_unsequenced_group, 1024);
+    }
+
+    #[test]
+    fn reliable_window_wrap_holds_back_commands() {
+        let sink = UdpSocket::bind((Ipv4Addr::LOCALHOST, 0)).unwrap();
+        let mut host = Host::create(localhost(), 1, 1, 0, 0).unwrap();
+        connect_locally(&mut host, 0, sink.local_addr().unwrap());
+
+        // the next command opens window 1 while window 9 is still unacknowledged
+        let channel = &mut host.peer_mut(0).channels[0];
+        channel.outgoing_reliable_seq_num = PEER_RELIABLE_WINDOW_SIZE as u16 - 1;
+        channel.used_reliable_windows = 1 << 9;
+
+        host.send(0, 0, Packet::from_vec(vec![1, 2, 3], PACKET_FLAG_RELIABLE)).unwrap();
+        host.flush().unwrap();
+        assert_eq!(host.peer(0).outgoing_send_reliable_commands.len(), 1);
+
+        host.peer_mut(0).channels[0].used_reliable_windows = 0;
+        host.flush().unwrap();
+
+        let peer = host.peer(0);
+        assert!(peer.outgoing_send_reliable_commands.is_empty());
+        assert_eq!(peer.channels[0].reliable_windows[1], 1);
+        assert_eq!(peer.channels[0].used_reliable_windows, 1 << 1);
+    }
+
+    #[test]
+    fn large_packets_are_fragmented() {
+        let (mut server, mut client, peer) = connected_pair(1);
+        let fragment_length = client.peer(peer).mtu as usize - mem::size_of::<ProtocolHeader>() - mem::size_of::<ProtocolSendFragment>();
+
+        let blob: Vec<u8> = (0..200 * 1024).map(|i: u32| (i * 7 + i / 251) as u8).collect();
+        client.send(peer, 0, Packet::from_vec(blob[..5000].to_vec(), PACKET_FLAG_UNRELIABLE_FRAGMENT)).unwrap();
+        assert_eq!(client.peer(peer).outgoing_commands.len(), 5000usize.div_ceil(fragment_length));
+
+        let event = pump(&mut server, &mut client);
+        let packet = event.packet.unwrap();
+        assert_eq!(packet.flags, PACKET_FLAG_UNRELIABLE_FRAGMENT);
+        assert_eq!(&packet.data[..], &blob[..5000]);
+
+        client.send(peer, 0, Packet::from_vec(blob.clone(), PACKET_FLAG_RELIABLE)).unwrap();
+        let fragments = client.peer(peer).outgoing_send_reliable_commands.len();
+        assert_eq!(fragments, blob.len().div_ceil(fragment_length));
+
+        let event = pump(&mut server, &mut client);
+        let packet = event.packet.unwrap();
+        assert_eq!(packet.flags, PACKET_FLAG_RELIABLE);
+        assert_eq!(*packet.data, blob);
+
+        // every fragment took up a reliable sequence number of its own
+        let channel = &server.peer(0).channels[0];
+        assert_eq!(channel.incoming_reliable_seq_num as usize, fragments);
+        assert_eq!(server.peer(0).total_waiting_data, 0);
+    }
+
+    #[test]
+    fn compressed_datagrams_are_received() {
+        let (mut server, mut client, peer) = connected_pair(1);
+        server.compress_with_range_coder();
+        client.compress_with_range_coder();
+
+        let data = b"position 10 20 30 velocity 1 2 3 ".repeat(30);
+        let sent_data = client.total_sent_data;
+        client.send(peer, 0, Packet::from_vec(data.clone(), 0)).unwrap();
+        client.flush().unwrap();
+        assert!(client.total_sent_data - sent_data < data.len() as u32 / 2);
+
+        let event = pump(&mut server, &mut client);
+        assert_eq!(*event.packet.unwrap().data, data);
+    }
+
+    /// Claims success without saving anything, so nothing should be flagged
+    struct Incompressible;
+
+    impl Compressor for Incompressible {
+        fn compress(&mut self, _: &[&[u8]], in_limit: usize, _: &mut [u8]) -> Option<usize> {
+            Some(in_limit)
+        }
+
+        fn decompress(&mut self, _: &[u8], _: &mut [u8]) -> Option<usize> {
+            None
+        }
+    }
+
+    #[test]
+    fn compression_is_skipped_when_it_does_not_help() {
+        let (mut server, mut client, peer) = connected_pair(1);
+        client.compress(Some(Box::new(Incompressible)));
+
+        client.send(peer, 0, Packet::from_vec(vec![1, 2, 3], 0)).unwrap();
+        let event = pump(&mut server, &mut client);
+        assert_eq!(&event.packet.unwrap().data[..], [1, 2, 3]);
+
+        // a host without a compressor has no way to read compressed datagrams
+        client.compress_with_range_coder();
+        server.compress(None);
+        client.send(peer, 0, Packet::from_vec(vec![7; 500], 0)).unwrap();
+        for _ in 0..20 {
+            assert!(server.service(5).unwrap().is_none());
+            assert!(client.service(5).unwrap().is_none());
+        }
+    }
+
+    #[test]
+    fn checksummed_datagrams_are_received() {
+        let mut server = Host::create(localhost(), 1, 1, 0, 0).unwrap();
+        let mut client = Host::create(localhost(), 1, 1, 0, 0).unwrap();
+        server.checksum = Some(crc32);
+        client.checksum = Some(crc32);
+
+        let peer = client.connect(server.address, 1, 0).unwrap();
+        pump(&mut client, &mut server);
+        pump(&mut server, &mut client);
+
+        // the checksum is taken before compression and checked after it
+        server.compress_with_range_coder();
+        client.compress_with_range_coder();
+
+        let data: Vec<u8> = (0..5000u32).map(|i| (i % 13) as u8).collect();
+        client.send(peer, 0, Packet::from_vec(data.clone(), PACKET_FLAG_RELIABLE)).unwrap();
+        let event = pump(&mut server, &mut client);
+        assert_eq!(*event.packet.unwrap().data, data);
+    }
+
+    #[test]
+    fn corrupted_datagrams_are_dropped() {
+        let mut server = Host::create(localhost(), 1, 1, 0, 0).unwrap();
+        let mut client = Host::create(localhost(), 1, 1, 0, 0).unwrap();
+        server.checksum = Some(crc32);
+        client.checksum = Some(crc32);
+
+        client.connect(server.address, 1, 0).unwrap();
+        client.flush().unwrap();
+
+        let mut buffer = [0u8; MAXIMUM_MTU as usize];
+        server.socket.as_ref().unwrap().set_nonblocking(false).unwrap();
+        let (length, _) = server.socket.as_ref().unwrap().recv_from(&mut buffer).unwrap();
+        server.socket.as_ref().unwrap().set_nonblocking(true).unwrap();
+
+        let sender = UdpSocket::bind((Ipv4Addr::LOCALHOST, 0)).unwrap();
+        let mut corrupted = buffer[..length].to_vec();
+        corrupted[length - 1] ^= 0x10;
+        sender.send_to(&corrupted, server.address).unwrap();
+        assert!(server.service(20).unwrap().is_none());
+        assert_eq!(server.peer(0).state, PeerState::Disconnected);
+
+        sender.send_to(&buffer[..length], server.address).unwrap();
+        assert!(server.service(20).unwrap().is_none());
+        assert_eq!(server.peer(0).state, PeerState::AcknowledgingConnect);
+    }
+
+    #[test]
+    fn packets_are_shared_until_acknowledged() {
+        let mut server = Host::create(localhost(), 2, 1, 0, 0).unwrap();
+        let mut clients = [Host::create(localhost(), 1, 1, 0, 0).unwrap(), Host::create(localhost(), 1, 1, 0, 0).unwrap()];
+
+        for client in &mut clients {
+            client.connect(server.address, 1, 0).unwrap();
+            pump(client, &mut server);
+            pump(&mut server, client);
+        }
+
+        let packet = Packet::from_vec(vec![0; 3000], PACKET_FLAG_RELIABLE);
+        server.broadcast(0, packet.clone());
+        assert_eq!(packet.reference_count(), 7);
+        for peer in &server.peers {
+            assert_eq!(peer.outgoing_send_reliable_commands.len(), 3);
+            assert!(peer.outgoing_send_reliable_commands.iter().all(|command| Arc::ptr_eq(&command.packet.as_ref().unwrap().data, &packet.data)));
+        }
+
+        for client in &mut clients {
+            let event = pump(client, &mut server);
+            assert_eq!(event.packet.unwrap().data_length(), 3000);
+        }
+
+        // only our handle is left once every fragment has been acknowledged
+        for _ in 0..20 {
+            server.service(5).unwrap();
+            clients.iter_mut().for_each(|client| assert!(client.service(5).unwrap().is_none()));
+        }
+        assert_eq!(packet.reference_count(), 1);
+    }
+
+    /// Creates a reliable packet whose free callback stores how it was released
+    fn tracked_packet() -> (Packet, Arc<Mutex<Option<PacketRelease>>>) {
+        let released = Arc::new(Mutex::new(None));
+
+        let mut packet = Packet::from_vec(vec![1, 2, 3], PACKET_FLAG_RELIABLE);
+        let slot = released.clone();
+        packet.set_free_callback(move |release| *slot.lock().unwrap() = Some(release));
+
+        (packet, released)
+    }
+
+    #[test]
+    fn free_callback_runs_once_acknowledged() {
+        let (mut server, mut client, peer) = connected_pair(1);
+        let (packet, released) = tracked_packet();
+        client.send(peer, 0, packet).unwrap();
+
+        client.flush().unwrap();
+        assert_eq!(client.peer(peer).sent_reliable_commands.len(), 1);
+        assert_eq!(*released.lock().unwrap(), None);
+
+        pump(&mut server, &mut client);
+        for _ in 0..20 {
+            if released.lock().unwrap().is_some() {
+                break;
+            }
+            assert!(client.service(5).unwrap().is_none());
+        }
+        assert_eq!(*released.lock().unwrap(), Some(PacketRelease::Acknowledged));
+        assert!(client.peer(peer).sent_reliable_commands.is_empty());
+    }
+
+    #[test]
+    fn free_callback_reports_unacknowledged_packets_as_dropped() {
+        let (_server, mut client, peer) = connected_pair(1);
+        let (packet, released) = tracked_packet();
+        client.send(peer, 0, packet).unwrap();
+
+        client.flush().unwrap();
+        assert_eq!(client.peer(peer).sent_reliable_commands.len(), 1);
+
+        client.disconnect_now(peer, 0).unwrap();
+        assert_eq!(*released.lock().unwrap(), Some(PacketRelease::Dropped));
+    }
+
+    #[test]
+    fn send_rejects_invalid_requests() {
+        let (_server, mut client, peer) = connected_pair(1);
+
+        assert_eq!(client.send(peer, 1, Packet::from_vec(vec![0], PACKET_FLAG_RELIABLE)), Err(PeerError::InvalidChannel(1)));
+        client.maximum_packet_size = 1024;
+        assert_eq!(client.send(peer, 0, Packet::from_vec(vec![0; 4096], PACKET_FLAG_RELIABLE)), Err(PeerError::PacketTooLarge(4096)));
+
+        client.disconnect_now(peer, 0).unwrap();
+        assert_eq!(client.send(peer, 0, Packet::from_vec(vec![0], PACKET_FLAG_RELIABLE)), Err(PeerError::InvalidState(PeerState::Disconnected)));
+    }
+
+    #[test]
+    fn retransmits_lost_connect() {
+        let mut server = Host::create(localhost(), 1, 1, 0, 0).unwrap();
+        let mut client = Host::create(localhost(), 1, 1, 0, 0).unwrap();
+
+        let peer = client.connect(server.address, 1, 0).unwrap();
+        client.peer_mut(peer).roundtrip_time = 20;
+        client.flush().unwrap();
+
+        // drop the first connect before the server gets to see it
+        let mut buffer = [0u8; MAXIMUM_MTU as usize];
+        server.socket.as_ref().unwrap().set_nonblocking(false).unwrap();
+        server.socket.as_ref().unwrap().recv_from(&mut buffer).unwrap();
+        server.socket.as_ref().unwrap().set_nonblocking(true).unwrap();
+
+        let event = pump(&mut client, &mut server);
+        assert_eq!(event.event_type, EventType::Connect);
+
+        let peer = client.peer(peer);
+        assert_eq!(peer.packets_lost, 1);
+        assert!(peer.sent_reliable_commands.is_empty());
+    }
+
+    #[test]
+    fn connect_times_out() {
+        let mut simulator = Simulator::new(0);
+        simulator.add_host(1, 1, 0, 0).unwrap();
+
+        // nothing answers at an address no simulated host owns
+        let peer = simulator.host_mut(0).connect(SocketAddr::from((Ipv4Addr::LOCALHOST, 9)), 1, 0).unwrap();
+        let peer_mut = simulator.host_mut(0).peer_mut(peer);
+        peer_mut.roundtrip_time = 5;
+        peer_mut.set_timeout(0, 50, 200);
+
+        let (_, event) = simulator.next_event(1000).expect("peer never timed out");
+        assert_eq!(event.event_type, EventType::DisconnectTimeout);
+        assert_eq!(event.peer, peer);
+        // the timeout is noticed when the next retransmission falls due
+        assert!((200..400).contains(&simulator.elapsed()), "timed out after {}ms", simulator.elapsed());
+        assert_eq!(simulator.host(0).peer(peer).state, PeerState::Disconnected);
+    }
+
+    #[test]
+    fn mock_clock_drives_timeouts_across_wraparound() {
+        let silent = UdpSocket::bind((Ipv4Addr::LOCALHOST, 0)).unwrap();
+        let clock = MockClock::new(u32::MAX - 200);
+        let mut client = Host::create(localhost(), 1, 1, 0, 0).unwrap();
+        client.set_clock(Box::new(clock.clone()));
+
+        let peer = client.connect(silent.local_addr().unwrap(), 1, 0).unwrap();
+        assert!(client.service(0).unwrap().is_none());
+        assert_eq!(client.total_sent_packets, 1);
+
+        // the first retransmission is due one default round trip later, past the wrap
+        let next_timeout = client.peer(peer).next_timeout;
+        assert!(next_timeout < 1000);
+        clock.set(next_timeout - 1);
+        assert!(client.service(0).unwrap().is_none());
+        assert_eq!(client.total_sent_packets, 1);
+
+        clock.advance(1);
+        assert!(client.service(0).unwrap().is_none());
+        assert_eq!(client.total_sent_packets, 2);
+
+        let event = (0..100).find_map(|_| {
+            clock.advance(1000);
+            client.service(0).unwrap()
+        }).expect("peer never timed out");
+        assert_eq!(event.event_type, EventType::DisconnectTimeout);
+
+        // retransmissions back off from 500ms, the sixth one giving up
+        assert_eq!(time_difference(clock.now(), u32::MAX - 200), 500 + 1000 + 2000 + 4000 + 8000 + 16000);
+    }
+
+    #[test]
+    fn silent_peers_time_out() {
+        let (mut simulator, peer) = simulated_pair();
+        simulator.host_mut(0).peer_mut(0).set_pinging(false);
+        simulator.host_mut(1).peer_mut(peer).set_timeout(4, 100, 300);
+        simulator.host_mut(1).send(peer, 0, Packet::from_vec(vec![1], PACKET_FLAG_RELIABLE)).unwrap();
+        simulator.set_link(1, 0, LinkConditions { loss: 1.0, ..LinkConditions::default() });
+
+        let start = simulator.elapsed();
+        let (index, event) = simulator.next_event(1000).expect("peer never timed out");
+        assert_eq!((index, event.event_type), (1, EventType::DisconnectTimeout));
+        assert_eq!(event.peer, peer);
+
+        let waited = simulator.elapsed() - start;
+        assert!((300..600).contains(&waited), "timed out after {waited}ms");
+
+        // a clean disconnect is still reported as such
+        let (mut simulator, peer) = simulated_pair();
+        simulator.host_mut(1).disconnect(peer, 0).unwrap();
+        let (index, event) = simulator.next_event(1000).expect("disconnect never arrived");
+        assert_eq!((index, event.event_type), (0, EventType::Disconnect));
+    }
+
+    /// Puts a peer of `host` straight into the connected state, talking to `address`
+    fn connect_locally(host: &mut Host, id: PeerId, address: SocketAddr) {
+        host.setup_channels(id, 1);
+        host.change_state(id, PeerState::AcknowledgingConnect);
+        host.change_state(id, PeerState::ConnectionPending);
+        host.change_state(id, PeerState::Connected);
+
+        let peer = host.peer_mut(id);
+        peer.address = address;
+        peer.outgoing_peer_id = 0;
+    }
+
+    fn ping(channel_id: u8, reliable_sequence_number: u16) -> Protocol {
+        Protocol::Ping(crate::protocol::ProtocolPing {
+            header: ProtocolCommandHeader { command: ProtocolCommand::Ping as u8 | COMMAND_FLAG_ACKNOWLEDGE, channel_id, reliable_sequence_number },
+        })
+    }
+
+    #[test]
+    fn acknowledgements_are_batched() {
+        let sink = UdpSocket::bind((Ipv4Addr::LOCALHOST, 0)).unwrap();
+        let mut host = Host::create(localhost(), 1, 1, 0, 0).unwrap();
+        connect_locally(&mut host, 0, sink.local_addr().unwrap());
+
+        for sequence_number in 0..40 {
+            host.peer_mut(0).queue_acknowledgement(&ping(0xFF, sequence_number), sequence_number * 10);
+        }
+        host.peer_mut(0).queue_outgoing_command(ping(0xFF, 0), None, 0, 0);
+        host.flush().unwrap();
+
+        let mut acknowledged = Vec::new();
+        let mut commands_per_datagram = Vec::new();
+        let mut buffer = [0u8; MAXIMUM_MTU as usize];
+        for _ in 0..2 {
+            let length = sink.recv(&mut buffer).unwrap();
+            let (_, header_size) = ProtocolHeader::decode(&buffer[..length]).unwrap();
+
+            let commands: Vec<_> = Commands::new(&buffer[header_size..length]).map(|command| command.unwrap().0).collect();
+            commands_per_datagram.push(commands.len());
+
+            for command in commands {
+                if let Protocol::Acknowledge(acknowledge) = command {
+                    let (sequence_number, sent_time) = (acknowledge.received_reliable_sequence_number, acknowledge.received_sent_time);
+                    assert_eq!(sequence_number as usize, acknowledged.len());
+                    assert_eq!(sent_time, sequence_number * 10);
+                    acknowledged.push(sequence_number);
+                } else {
+                    assert_eq!(command.command(), Some(ProtocolCommand::Ping));
+                    assert_eq!(acknowledged.len(), 40, "data was sent ahead of acknowledgements");
+                }
+            }
+        }
+
+        assert_eq!(commands_per_datagram, [MAXIMUM_PACKET_COMMANDS as usize, 9]);
+        assert!(host.peer(0).acknowledgements.is_empty());
+    }
+
+    #[test]
+    fn acknowledgements_respect_free_reliable_windows() {
+        let mut host = Host::create(localhost(), 1, 1, 0, 0).unwrap();
+        connect_locally(&mut host, 0, SocketAddr::from((Ipv4Addr::LOCALHOST, 9)));
+
+        let window = PEER_RELIABLE_WINDOW_SIZE as u16;
+        let peer = host.peer_mut(0);
+        peer.queue_acknowledgement(&ping(0, 6 * window), 0);
+        peer.queue_acknowledgement(&ping(0, 7 * window), 0);
+        peer.queue_acknowledgement(&ping(0, 8 * window), 0);
+        peer.queue_acknowledgement(&ping(0, 9 * window), 0);
+
+        // behind the current sequence number, so the window index wraps around
+        peer.channels[0].incoming_reliable_seq_num = 10 * window;
+        peer.queue_acknowledgement(&ping(0, window), 0);
+        peer.queue_acknowledgement(&ping(0, 3 * window), 0);
+
+        let acknowledged: Vec<_> = peer.acknowledgements.iter().map(|ack| ack.command.header().reliable_sequence_number / window).collect();
+        assert_eq!(acknowledged, [6, 9, 3]);
+    }
+
+    fn announce_bandwidth_limit(server: &mut Host, client: &mut Host, peer: PeerId, incoming_bandwidth: u32) {
+        // peers that do not limit their own outgoing bandwidth are left alone
+        server.peer_mut(0).outgoing_bandwidth = 1_000_000;
+        server.bandwidth_limit(incoming_bandwidth, 0);
+        server.bandwidth_throttle_epoch = server.time_get().wrapping_sub(constants::HOST_BANDWIDTH_THROTTLE_INTERVAL);
+
+        for _ in 0..100 {
+            if client.peer(peer).incoming_bandwidth == incoming_bandwidth {
+                return;
+            }
+            assert!(server.service(5).unwrap().is_none());
+            assert!(client.service(5).unwrap().is_none());
+        }
+
+        panic!("bandwidth limit was not received");
+    }
+
+    #[test]
+    fn bandwidth_limits_are_announced() {
+        let (mut server, mut client, peer) = connected_pair(1);
+        announce_bandwidth_limit(&mut server, &mut client, peer, 64000);
+
+        assert!(!server.recalculate_bandwidth_limits);
+        assert_eq!(client.bandwidth_limited_peers, 1);
+        assert_eq!(client.peer(peer).window_size, MINIMUM_WINDOW_SIZE);
+
+        announce_bandwidth_limit(&mut server, &mut client, peer, 0);
+        assert_eq!(client.bandwidth_limited_peers, 0);
+        assert_eq!(client.peer(peer).window_size, MAXIMUM_WINDOW_SIZE);
+    }
+
+    #[test]
+    fn bandwidth_limited_peers_are_throttled() {
+        let (mut server, mut client, peer) = connected_pair(1);
+        announce_bandwidth_limit(&mut server, &mut client, peer, 2000);
+
+        client.peer_mut(peer).outgoing_data_total = 8000;
+        client.bandwidth_throttle_epoch = client.service_time.wrapping_sub(constants::HOST_BANDWIDTH_THROTTLE_INTERVAL);
+        client.bandwidth_throttle();
+
+        let peer = client.peer(peer);
+        assert_eq!(peer.packet_throttle_limit, 8);
+        assert!(peer.packet_throttle <= 8);
+    }
+
+    fn sans_io_host(port: u16) -> Host {
+        Host::new(SocketAddr::from((Ipv4Addr::LOCALHOST, port)), 1, 1, 0, 0).unwrap()
+    }
+
+    /// Hands every datagram `from` wants to send at `now` over to `to`
+    fn deliver(from: &mut Host, to: &mut Host, now: u32) -> usize {
+        let mut count = 0;
+        while let Some(transmit) = from.poll_transmit(now) {
+            assert_eq!(transmit.address, to.address);
+            to.handle_datagram(now, from.address, &transmit.data);
+            count += 1;
+        }
+        count
+    }
+
+    #[derive(Debug, Clone, PartialEq, Hash)]
+    struct Endpoint(u8);
+
+    impl Address for Endpoint {}
+
+    /// One end of an in-memory link between two endpoints
+    struct ChannelSocket {
+        address: Endpoint,
+        remote: Endpoint,
+        outgoing: Sender<Vec<u8>>,
+        incoming: Receiver<Vec<u8>>,
+    }
+
+    impl Socket for ChannelSocket {
+        type Address = Endpoint;
+
+        fn local_address(&self) -> io::Result<Endpoint> {
+            Ok(self.address.clone())
+        }
+
+        fn send_to(&mut self, data: &[u8], address: &Endpoint) -> io::Result<usize> {
+            if *address == self.remote {
+                let _ = self.outgoing.send(data.to_vec());
+            }
+            Ok(data.len())
+        }
+
+        fn recv_from(&mut self, buffer: &mut [u8]) -> io::Result<(usize, Endpoint)> {
+            let data = self.incoming.try_recv().map_err(|_| io::Error::from(io::ErrorKind::WouldBlock))?;
+            buffer[..data.len()].copy_from_slice(&data);
+            Ok((data.len(), self.remote.clone()))
+        }
+    }
+
+    #[test]
+    fn hosts_run_over_custom_sockets() {
+        let (to_server, server_incoming) = channel();
+        let (to_client, client_incoming) = channel();
+        let server_socket = ChannelSocket { address: Endpoint(0), remote: Endpoint(1), outgoing: to_client, incoming: server_incoming };
+        let client_socket = ChannelSocket { address: Endpoint(1), remote: Endpoint(0), outgoing: to_server, incoming: client_incoming };
+
+        let mut server = Host::with_socket(server_socket, 1, 1, 0, 0).unwrap();
+        let mut client = Host::with_socket(client_socket, 1, 1, 0, 0).unwrap();
+
+        let peer = client.connect(Endpoint(0), 1, 0).unwrap();
+        assert_eq!(pump(&mut client, &mut server).event_type, EventType::Connect);
+        assert_eq!(pump(&mut server, &mut client).event_type, EventType::Connect);
+        assert_eq!(server.peer(0).address, Endpoint(1));
+
+        client.send(peer, 0, Packet::from_vec(vec![5; 4000], PACKET_FLAG_RELIABLE)).unwrap();
+        let event = pump(&mut server, &mut client);
+        assert_eq!(event.event_type, EventType::Receive);
+        assert_eq!(*event.packet.unwrap().data, vec![5; 4000]);
+    }
+
+    #[test]
+    fn sans_io_hosts_exchange_packets() {
+        let mut server = sans_io_host(1);
+        let mut client = sans_io_host(2);
+
+        let peer = client.connect(server.address, 1, 42).unwrap();
+        assert_eq!(deliver(&mut client, &mut server, 0), 1);
+        assert!(server.poll_event().is_none());
+
+        deliver(&mut server, &mut client, 10);
+        let event = client.poll_event().unwrap();
+        assert_eq!(event.event_type, EventType::Connect);
+        assert_eq!(event.peer, peer);
+
+        // the server only reports the peer once its verification is acknowledged
+        deliver(&mut client, &mut server, 20);
+        let event = server.poll_event().unwrap();
+        assert_eq!(event.event_type, EventType::Connect);
+        assert_eq!(event.data, 42);
+
+        client.send(peer, 0, Packet::from_vec(vec![1, 2, 3], PACKET_FLAG_RELIABLE)).unwrap();
+        deliver(&mut client, &mut server, 20);
+        let event = server.poll_event().unwrap();
+        assert_eq!(event.event_type, EventType::Receive);
+        assert_eq!(*event.packet.unwrap().data, vec![1, 2, 3]);
+        assert!(server.poll_event().is_none());
+
+        // the acknowledgement is due right away and settles the packet
+        assert_eq!(server.poll_timeout(), Some(20));
+        deliver(&mut server, &mut client, 30);
+        assert!(client.peer(peer).sent_reliable_commands.is_empty());
+    }
+
+    #[test]
+    fn poll_timeout_schedules_retransmissions() {
+        let mut server = sans_io_host(1);
+        let mut client = sans_io_host(2);
+        assert_eq!(client.poll_timeout(), None);
+
+        client.connect(server.address, 1, 0).unwrap();
+        assert!(client.poll_transmit(1000).is_some());
+        assert!(client.poll_transmit(1000).is_none());
+
+        let timeout = client.poll_timeout().unwrap();
+        assert!(time_less(1000, timeout));
+        assert!(client.poll_transmit(timeout - 1).is_none());
+
+        // the first connect went missing, the retransmission gets through
+        assert_eq!(deliver(&mut client, &mut server, timeout), 1);
+        assert_eq!(server.peer(0).state, PeerState::AcknowledgingConnect);
+    }
+
+    #[test]
+    fn service_needs_a_socket() {
+        let mut host = sans_io_host(1);
+
+        assert!(host.service(0).is_err_and(|err| err.kind() == io::ErrorKind::Unsupported));
+        assert!(host.flush().is_ok());
+    }
+}
@@ -6,7 +6,12 @@ pub mod event;
 pub mod command;
 pub mod compress;
 pub mod host;
+#[cfg(feature = "tokio")]
+pub mod async_host;
 pub mod peer;
+pub mod socket;
+pub mod time;
+pub mod simulator;
 
 pub const VERSION_MAJOR: u8 = 1;
 pub const VERSION_MINOR: u8 = 3;
@@ -1,4 +1,4 @@
-use std::borrow::Cow;
+use std::{any::Any, fmt, sync::{atomic::{AtomicBool, Ordering}, Arc, Mutex}};
 
 pub mod constants {
     pub const PACKET_FLAG_RELIABLE: u32 = 1 << 0;
@@ -8,75 +8,146 @@ pub mod constants {
     pub const PACKET_FLAG_SENT: u32 = 1 << 8;
 }
 
-#[derive(Copy)]
-pub struct Packet<'a> {
-    pub ref_count: usize,
+/// What became of a packet by the time it is released
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum PacketRelease {
+    /// Every command carrying it was acknowledged, or sent for an
+    /// unreliable packet
+    Acknowledged,
+    /// It was never sent, or a command carrying it was thrown away before
+    /// being delivered, for instance by a reset, a timeout or the throttle
+    Dropped,
+}
+
+pub type FreeCallbackFn = Box<dyn FnOnce(PacketRelease) + Send>;
+
+/// Runs a packet's free callback once the last handle sharing it is dropped
+pub struct FreeCallback {
+    callback: Mutex<Option<FreeCallbackFn>>,
+    acknowledged: AtomicBool,
+    dropped: AtomicBool,
+}
+
+impl Drop for FreeCallback {
+    fn drop(&mut self) {
+        let release = if *self.acknowledged.get_mut() && !*self.dropped.get_mut() {
+            PacketRelease::Acknowledged
+        } else {
+            PacketRelease::Dropped
+        };
+
+        if let Ok(callback) = self.callback.get_mut() &&
+           let Some(callback) = callback.take() {
+            callback(release);
+        }
+    }
+}
+
+impl fmt::Debug for FreeCallback {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str("FreeCallback")
+    }
+}
+
+/// A packet whose payload is shared by reference count.
+///
+/// Cloning is cheap, so the same packet can be queued for many peers and
+/// split into fragments without copying. The payload is freed once the
+/// last clone is gone, which for a sent packet is when its last command
+/// has been acknowledged or dropped.
+#[derive(Clone)]
+pub struct Packet {
     pub flags: u32,
-    pub data: &'a [u8],
-    pub data_length: usize, // prob not needed cus Vec<u8> but oh well
-    pub free_callback: Option<()>, // this is rust llol
-    pub user_data: Option<()>, // void pointer, maybe vec<u8> would do?
+    pub data: Arc<Vec<u8>>,
+    pub free_callback: Option<Arc<FreeCallback>>,
+    pub user_data: Option<Arc<dyn Any + Send + Sync>>,
 }
 
-impl<'a> Default for Packet<'a> {
+impl Default for Packet {
     fn default() -> Self {
         Self {
-            ref_count: 0,
             flags: constants::PACKET_FLAG_RELIABLE,
-            data: &[],
-            data_length: 0,
+            data: Arc::default(),
             free_callback: None,
             user_data: None
         }
     }
 }
 
-impl<'a> Clone for Packet<'a> {
-    fn clone(&self) -> Self {
-        Self {
-            ref_count: self.ref_count,
-            flags: self.flags,
-            data: self.data.clone(),
-            data_length: self.data_length,
-            free_callback: self.free_callback,
-            user_data: self.user_data,
-        }
+impl Packet {
+    /// Creates a packet holding a copy of `data`. Use `from_vec` to hand
+    /// over a buffer without copying, PACKET_FLAG_NO_ALLOCATE has no effect.
+    pub fn create(data: &[u8], flags: u32) -> Self {
+        Self::from_vec(data.to_vec(), flags)
     }
-}
-
-impl<'a> Packet<'a> {
-    pub fn create(data: &'a [u8], flags: u32) -> Self {
-        // let data_thing = if (flags & constants::PACKET_FLAG_NO_ALLOCATE) != 0 {
-        //     data
-        // } else {
-        //     data.clone()
-        // };
 
+    /// Creates a packet that takes ownership of `data`
+    pub fn from_vec(data: Vec<u8>, flags: u32) -> Self {
         Self {
-            ref_count: 0,
             flags,
-            data_length: data.len(),
-            data: data,
+            data: Arc::new(data),
             free_callback: None,
             user_data: None,
         }
     }
 
-    pub fn resize(&mut self, data_length: usize) {
-        let no_allocate = self.flags & constants::PACKET_FLAG_NO_ALLOCATE != 0;
+    pub fn data_length(&self) -> usize {
+        self.data.len()
+    }
+
+    /// Number of handles sharing the payload, including this one
+    pub fn reference_count(&self) -> usize {
+        Arc::strong_count(&self.data)
+    }
 
-        if no_allocate {
-            // if data_length > self.data.len() {
-            //     panic!("Cannot resize beyond current length with PACKET_FLAG_NO_ALLOCATE set");
-            // }
+    /// Gives mutable access to the payload, copying it first if it is shared
+    pub fn data_mut(&mut self) -> &mut Vec<u8> {
+        Arc::make_mut(&mut self.data)
+    }
 
-            self.data_length = data_length;
-        } else {
-            // welp this was a sacrifice for copy...
-            // self.data.to_mut().resize(data_length, 0);
-            self.data_length = data_length;
+    /// Sets a callback to run when the packet is released, once the
+    /// application and the host have both let go of it. It is told whether
+    /// every peer the packet was queued for got it. Only clones made after
+    /// this call share the callback.
+    pub fn set_free_callback(&mut self, callback: impl FnOnce(PacketRelease) + Send + 'static) {
+        self.free_callback = Some(Arc::new(FreeCallback {
+            callback: Mutex::new(Some(Box::new(callback))),
+            acknowledged: AtomicBool::new(false),
+            dropped: AtomicBool::new(false),
+        }));
+    }
+
+    /// Records that a command carrying the packet was acknowledged, or sent
+    /// if it is unreliable
+    pub fn mark_sent(&mut self) {
+        self.flags |= constants::PACKET_FLAG_SENT;
+        if let Some(free_callback) = &self.free_callback {
+            free_callback.acknowledged.store(true, Ordering::Relaxed);
+        }
+    }
+
+    /// Records that a command carrying the packet was discarded undelivered
+    pub fn mark_dropped(&self) {
+        if let Some(free_callback) = &self.free_callback {
+            free_callback.dropped.store(true, Ordering::Relaxed);
         }
     }
+
+    /// Attaches application data that travels with every clone of the packet
+    /// but is never sent
+    pub fn set_user_data<T: Any + Send + Sync>(&mut self, user_data: T) {
+        self.user_data = Some(Arc::new(user_data));
+    }
+
+    pub fn user_data<T: Any>(&self) -> Option<&T> {
+        self.user_data.as_deref()?.downcast_ref()
+    }
+
+    /// Grows or shrinks the payload, zero filling any new bytes. A payload
+    /// that is still queued elsewhere is copied rather than changed.
+    pub fn resize(&mut self, data_length: usize) {
+        self.data_mut().resize(data_length, 0);
+    }
 }
 
 // crc32 table
@@ -115,11 +186,13 @@ pub const CRC_TABLE: [u32; 256] = [
     0xB3667A2E, 0xC4614AB8, 0x5D681B02, 0x2A6F2B94, 0xB40BBE37, 0xC30C8EA1, 0x5A05DF1B, 0x2D02EF8D
 ];
 
-pub fn crc32(buffers: &Vec<Vec<u8>>) -> u32 {
+/// CRC32 over the concatenation of `buffers`, usable as a host checksum.
+/// Matches `enet_crc32` once written to the wire in big-endian order.
+pub fn crc32(buffers: &[&[u8]]) -> u32 {
     let mut crc: u32 = 0xFFFFFFFF;
 
     for buffer in buffers {
-        for &byte in buffer {
+        for &byte in buffer.iter() {
             let index = ((crc ^ (byte as u32)) & 0xFF) as usize;
             crc = (crc >> 8) ^ CRC_TABLE[index];
         }
@@ -134,7 +207,7 @@ mod tests {
 
     #[test]
     fn test_crc32() {
-        assert_eq!(crc32(&vec![vec![0u8, 0, 1], vec![0, 1, 1]]), 1734526737);
+        assert_eq!(crc32(&[&[0u8, 0, 1], &[0, 1, 1]]), 1734526737);
     }
 
     #[test]
@@ -142,8 +215,46 @@ mod tests {
         let data = b"asdf".to_vec();
         let pck = Packet::create(&data, constants::PACKET_FLAG_RELIABLE | constants::PACKET_FLAG_NO_ALLOCATE);
 
-        assert_eq!(pck.data, data);
-        assert_eq!(pck.data_length, data.len());
+        assert_eq!(*pck.data, data);
+        assert_eq!(pck.data_length(), data.len());
         assert_eq!(pck.flags, constants::PACKET_FLAG_RELIABLE | constants::PACKET_FLAG_NO_ALLOCATE);
     }
+
+    #[test]
+    fn resize_packet() {
+        let mut pck = Packet::from_vec(b"asdf".to_vec(), 0);
+        let shared = pck.clone();
+        assert_eq!(pck.reference_count(), 2);
+
+        pck.resize(6);
+        assert_eq!(*pck.data, b"asdf\0\0");
+        assert_eq!(*shared.data, b"asdf");
+        assert_eq!(pck.reference_count(), 1);
+
+        pck.resize(2);
+        assert_eq!(*pck.data, b"as");
+    }
+
+    #[test]
+    fn free_callback_runs_once_released() {
+        use std::sync::atomic::AtomicUsize;
+
+        let released = Arc::new(AtomicUsize::new(0));
+        let mut pck = Packet::from_vec(b"asdf".to_vec(), 0);
+        let counter = released.clone();
+        pck.set_free_callback(move |release| {
+            // never sent, so never acknowledged
+            assert_eq!(release, PacketRelease::Dropped);
+            counter.fetch_add(1, Ordering::SeqCst);
+        });
+        pck.set_user_data(42u32);
+
+        let clones = vec![pck.clone(), pck.clone()];
+        assert_eq!(clones[1].user_data::<u32>(), Some(&42));
+        assert_eq!(clones[1].user_data::<u64>(), None);
+
+        drop(pck);
+        drop(clones);
+        assert_eq!(released.load(Ordering::SeqCst), 1);
+    }
 }
\ No newline at end of file
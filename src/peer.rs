@@ -1,20 +1,12 @@
-use std::{cell::RefCell, net::Ipv4Addr, rc::Rc};
-use crate::{channel::Channel, command::OutgoingCommand, packet::Packet, peer::constants::{PEER_STATE_ACKNOWLEDGING_DISCONNECT, PEER_STATE_CONNECTED, PEER_STATE_DISCONNECTED, PEER_STATE_DISCONNECTING, PEER_STATE_DISCONNECT_LATER, PEER_STATE_ZOMBIE}, protocol::{command_size, constants::MAXIMUM_PEER_ID, flags::{COMMAND_FLAG_ACKNOWLEDGE, COMMAND_FLAG_UNSEQUENCED}, Protocol, ProtocolCommand, ProtocolCommandHeader, ProtocolThrottleConfigure}};
+use std::{collections::VecDeque, fmt, mem, net::SocketAddr};
+use crate::{channel::Channel, command::{Acknowledgement, IncomingCommand, OutgoingCommand}, packet::{constants::{PACKET_FLAG_RELIABLE, PACKET_FLAG_UNRELIABLE_FRAGMENT}, Packet}, peer::constants::*, protocol::{command_size, constants::{MAXIMUM_FRAGMENT_COUNT, MAXIMUM_MTU, MAXIMUM_PEER_ID, MAXIMUM_WINDOW_SIZE}, flags::{COMMAND_FLAG_ACKNOWLEDGE, COMMAND_FLAG_UNSEQUENCED}, Protocol, ProtocolCommand, ProtocolCommandHeader, ProtocolPing, ProtocolThrottleConfigure}, time::{time_difference, time_less}};
+
+/// Index of a peer inside its host's peer table
+pub type PeerId = usize;
 
 pub mod constants {
     use crate::protocol::constants::MAXIMUM_PACKET_COMMANDS;
 
-    pub const PEER_STATE_DISCONNECTED: u32                = 0;
-    pub const PEER_STATE_CONNECTING: u32                  = 1;
-    pub const PEER_STATE_ACKNOWLEDGING_CONNECT: u32       = 2;
-    pub const PEER_STATE_CONNECTION_PENDING: u32          = 3;
-    pub const PEER_STATE_CONNECTION_SUCCEEDED: u32        = 4;
-    pub const PEER_STATE_CONNECTED: u32                   = 5;
-    pub const PEER_STATE_DISCONNECT_LATER: u32            = 6;
-    pub const PEER_STATE_DISCONNECTING: u32               = 7;
-    pub const PEER_STATE_ACKNOWLEDGING_DISCONNECT: u32    = 8;
-    pub const PEER_STATE_ZOMBIE: u32                      = 9;
-
     pub const BUFFER_MAXIMUM: u32 = 1 + 2 * MAXIMUM_PACKET_COMMANDS;
 
     pub const PEER_DEFAULT_ROUND_TRIP_TIME: u32      = 500;
@@ -37,15 +29,111 @@ pub mod constants {
     pub const PEER_RELIABLE_WINDOWS: u32             = 16;
     pub const PEER_RELIABLE_WINDOW_SIZE: u32         = 0x1000;
     pub const PEER_FREE_RELIABLE_WINDOWS: u32        = 8;
+    pub const PEER_MTU_PROBE_INTERVAL: u32           = 5000;
+    pub const PEER_MTU_PROBE_PRECISION: u32          = 16;
     
-    pub const PEER_FLAG_NEEDS_DISPATCH: u32        = 1 << 0;
-    pub const PEER_FLAG_CONTINUE_SENDING: u32        = 1 << 1;
+    pub const PEER_FLAG_NEEDS_DISPATCH: u16          = 1 << 0;
+    pub const PEER_FLAG_CONTINUE_SENDING: u16        = 1 << 1;
+    pub const PEER_FLAG_NO_PINGS: u16                = 1 << 2;
+    pub const PEER_FLAG_TIMED_OUT: u16               = 1 << 3;
 }
 
-pub struct Peer<'a> {
-    pub dispatch_list: Option<()>,
-    pub host: Option<()>,
-    
+/// Connection state of a peer.
+///
+/// The variants are ordered like ENet's state constants, so comparisons such
+/// as `state >= PeerState::ConnectionPending` carry over from the C code.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash, Default)]
+pub enum PeerState {
+    #[default]
+    Disconnected = 0,
+    Connecting = 1,
+    AcknowledgingConnect = 2,
+    ConnectionPending = 3,
+    ConnectionSucceeded = 4,
+    Connected = 5,
+    DisconnectLater = 6,
+    Disconnecting = 7,
+    AcknowledgingDisconnect = 8,
+    Zombie = 9,
+}
+
+impl PeerState {
+    /// Whether the protocol ever moves a peer from `self` straight to `next`.
+    /// Resetting to `Disconnected` is always allowed.
+    pub fn can_transition_to(self, next: PeerState) -> bool {
+        use PeerState::*;
+
+        if next == Disconnected || next == self {
+            return true;
+        }
+
+        match self {
+            Disconnected => matches!(next, Connecting | AcknowledgingConnect),
+            Connecting => matches!(next, ConnectionSucceeded | Zombie),
+            AcknowledgingConnect => matches!(next, ConnectionPending | Zombie),
+            ConnectionPending | ConnectionSucceeded => matches!(next, Connected | Disconnecting | AcknowledgingDisconnect | Zombie),
+            Connected => matches!(next, DisconnectLater | Disconnecting | AcknowledgingDisconnect | Zombie),
+            DisconnectLater => matches!(next, Connected | Disconnecting | AcknowledgingDisconnect | Zombie),
+            Disconnecting | AcknowledgingDisconnect => next == Zombie,
+            Zombie => false,
+        }
+    }
+
+    /// Connected and DisconnectLater peers count towards the host's connected peers
+    pub fn is_connected(self) -> bool {
+        self == PeerState::Connected || self == PeerState::DisconnectLater
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum PeerError {
+    /// The peer is not in a state that allows the requested operation
+    InvalidState(PeerState),
+    /// The channel id is not below the peer's channel count
+    InvalidChannel(u8),
+    /// The packet is larger than the host allows
+    PacketTooLarge(usize),
+    /// The peer already holds the maximum amount of undelivered incoming data
+    WaitingDataExceeded,
+    /// A fragment does not fit the packet it claims to belong to
+    InvalidFragment,
+}
+
+impl fmt::Display for PeerError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            PeerError::InvalidState(state) => write!(f, "operation not allowed while the peer is {state:?}"),
+            PeerError::InvalidChannel(channel_id) => write!(f, "channel {channel_id} does not exist"),
+            PeerError::PacketTooLarge(length) => write!(f, "packet of {length} bytes exceeds the maximum packet size"),
+            PeerError::WaitingDataExceeded => write!(f, "too much incoming data is waiting to be received"),
+            PeerError::InvalidFragment => write!(f, "fragment does not match the packet it belongs to"),
+        }
+    }
+}
+
+impl std::error::Error for PeerError {}
+
+/// Snapshot of a peer's connection quality
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct PeerStats {
+    /// Smoothed round trip time in milliseconds
+    pub roundtrip_time: u32,
+    pub roundtrip_time_variance: u32,
+    /// Smoothed share of reliable packets lost, scaled by `PEER_PACKET_LOSS_SCALE`
+    pub packet_loss: u32,
+    pub packet_loss_variance: u32,
+    /// Share of unreliable packets let through, scaled by `PEER_PACKET_THROTTLE_SCALE`
+    pub packet_throttle: u32,
+}
+
+impl PeerStats {
+    /// Packet loss as a fraction between 0 and 1
+    pub fn packet_loss_ratio(&self) -> f32 {
+        self.packet_loss as f32 / PEER_PACKET_LOSS_SCALE as f32
+    }
+}
+
+pub struct Peer<A = SocketAddr> {
     pub outgoing_peer_id: u16,
     pub incoming_peer_id: u16,
     pub connect_id: u32,
@@ -53,10 +141,12 @@ pub struct Peer<'a> {
     pub outgoing_session_id: u8,
     pub incoming_session_id: u8,
     
-    pub address: Ipv4Addr,
+    /// Meaningless while the peer is disconnected
+    pub address: A,
     pub data: Option<()>, // void ptr
     
-    pub state: u32,
+    /// Read through `Peer::state`, only the host moves a peer between states
+    pub(crate) state: PeerState,
     
     pub channels: Vec<Channel>, // ENetChannel*
     pub channel_count: usize,
@@ -103,15 +193,24 @@ pub struct Peer<'a> {
     pub roundtrip_time_variance: u32,
 
     pub mtu: u32,
+    /// MTU agreed on while connecting, which a lossy path falls back to
+    pub negotiated_mtu: u32,
     pub window_size: u32,
     pub reliable_data_in_transit: u32,
     pub outgoing_reliable_seq_num: u16,
 
-    pub acknowledgements: Vec<()>, // ENetList
-    pub sent_reliable_commands: Vec<()>, // ENetList
-    pub outgoing_send_reliable_commands: Vec<OutgoingCommand<'a>>, // ENetList
-    pub outgoing_commands: Vec<OutgoingCommand<'a>>, // ENetList
-    pub dispatched_commands: Vec<()>, // ENetList
+    /// Datagram size of the path-MTU probe in flight, 0 when there is none
+    pub mtu_probe: u32,
+    pub mtu_probe_seq_num: u16,
+    /// Smallest datagram size known not to get through
+    pub mtu_probe_limit: u32,
+    pub mtu_probe_time: u32,
+
+    pub acknowledgements: Vec<Acknowledgement>, // ENetList
+    pub sent_reliable_commands: Vec<OutgoingCommand>, // ENetList
+    pub outgoing_send_reliable_commands: Vec<OutgoingCommand>, // ENetList
+    pub outgoing_commands: Vec<OutgoingCommand>, // ENetList
+    pub dispatched_commands: VecDeque<IncomingCommand>, // ENetList
 
     pub flags: u16,
     pub reserved: u16,
@@ -120,24 +219,92 @@ pub struct Peer<'a> {
 
     pub unsequenced_window: Box<[u32]>, // size constants::PEER_UNSEQUENCED_WINDOW_SIZE / 32
     pub event_data: u32,
-    pub total_waiting_data: usize
+    pub total_waiting_data: usize,
+    pub total_queued: u32,
 }
 
-impl<'a> Peer<'a> {
-    pub fn disconnect(&mut self, data: u32) {
-        if self.state == PEER_STATE_DISCONNECTING || self.state == PEER_STATE_DISCONNECTED ||
-           self.state == PEER_STATE_ACKNOWLEDGING_DISCONNECT || self.state == PEER_STATE_ZOMBIE {
-            return
-        }
+impl<A> Peer<A> {
+    pub fn new(incoming_peer_id: u16, address: A) -> Self {
+        let mut peer = Self {
+            outgoing_peer_id: MAXIMUM_PEER_ID as u16,
+            incoming_peer_id,
+            connect_id: 0,
+            outgoing_session_id: 0xFF,
+            incoming_session_id: 0xFF,
+            address,
+            data: None,
+            state: PeerState::Disconnected,
+            channels: Vec::new(),
+            channel_count: 0,
+            incoming_bandwidth: 0,
+            outgoing_bandwidth: 0,
+            incoming_bandwidth_throttle_epoch: 0,
+            outgoing_bandwidth_throttle_epoch: 0,
+            incoming_data_total: 0,
+            outgoing_data_total: 0,
+            last_send_time: 0,
+            last_receive_time: 0,
+            next_timeout: 0,
+            earliest_timeout: 0,
+            packet_loss_epoch: 0,
+            packets_sent: 0,
+            packets_lost: 0,
+            packet_loss: 0,
+            packet_loss_variance: 0,
+            packet_throttle: 0,
+            packet_throttle_limit: 0,
+            packet_throttle_counter: 0,
+            packet_throttle_epoch: 0,
+            packet_throttle_accel: 0,
+            packet_throttle_decel: 0,
+            packet_throttle_interval: 0,
+            ping_interval: 0,
+            timeout_limit: 0,
+            timeout_minimum: 0,
+            timeout_maximum: 0,
+            last_roundtrip_time: 0,
+            lowest_roundtrip_time: 0,
+            last_roundtrip_time_variance: 0,
+            highest_roundtrip_time_variance: 0,
+            roundtrip_time: 0,
+            roundtrip_time_variance: 0,
+            mtu: 0,
+            negotiated_mtu: 0,
+            window_size: 0,
+            reliable_data_in_transit: 0,
+            mtu_probe: 0,
+            mtu_probe_seq_num: 0,
+            mtu_probe_limit: 0,
+            mtu_probe_time: 0,
+            outgoing_reliable_seq_num: 0,
+            acknowledgements: Vec::new(),
+            sent_reliable_commands: Vec::new(),
+            outgoing_send_reliable_commands: Vec::new(),
+            outgoing_commands: Vec::new(),
+            dispatched_commands: VecDeque::new(),
+            flags: 0,
+            reserved: 0,
+            incoming_unsequenced_group: 0,
+            outgoing_unsequenced_group: 0,
+            unsequenced_window: vec![0; (PEER_UNSEQUENCED_WINDOW_SIZE / 32) as usize].into_boxed_slice(),
+            event_data: 0,
+            total_waiting_data: 0,
+            total_queued: 0,
+        };
 
+        peer.reset();
+        peer
+    }
 
+    pub fn state(&self) -> PeerState {
+        self.state
     }
 
     pub fn throttle(&mut self, rtt: u32) -> i32 {
         if self.last_roundtrip_time <= self.last_roundtrip_time_variance {
             self.packet_throttle = self.packet_throttle_limit;
         } else if rtt <= self.last_roundtrip_time {
-            self.packet_throttle += self.packet_throttle_accel;
+            self.packet_throttle = self.packet_throttle.saturating_add(self.packet_throttle_accel);
 
             if self.packet_throttle > self.packet_throttle_limit {
                 self.packet_throttle = self.packet_throttle_limit;
@@ -152,104 +319,816 @@ impl<'a> Peer<'a> {
             }
 
             return -1;
-        } 
+        }
 
-        return 0;
+        0
     }
 
-    pub fn receive(&mut self, channel_id: Option<u8>) -> Option<Packet<'a>> {
-        if self.dispatched_commands.is_empty() {
-            return None;
+    /// Takes the next packet that is ready for the application, along with
+    /// the channel it arrived on
+    pub fn receive(&mut self) -> Option<(u8, Packet)> {
+        let incoming_command = self.dispatched_commands.pop_front()?;
+        self.total_waiting_data -= incoming_command.packet.data_length();
+
+        Some((incoming_command.command.header().channel_id, incoming_command.packet))
+    }
+
+    /// Returns the peer to its freshly allocated state, dropping every queued
+    /// command. Connection bookkeeping on the host is handled by
+    /// `Host::reset_peer`, which should be preferred over calling this directly.
+    pub(crate) fn reset(&mut self) {
+        self.outgoing_peer_id = MAXIMUM_PEER_ID as u16;
+        self.connect_id = 0;
+
+        self.state = PeerState::Disconnected;
+
+        self.incoming_bandwidth = 0;
+        self.outgoing_bandwidth = 0;
+        self.incoming_bandwidth_throttle_epoch = 0;
+        self.outgoing_bandwidth_throttle_epoch = 0;
+        self.incoming_data_total = 0;
+        self.outgoing_data_total = 0;
+        self.last_send_time = 0;
+        self.last_receive_time = 0;
+        self.next_timeout = 0;
+        self.earliest_timeout = 0;
+        self.packet_loss_epoch = 0;
+        self.packets_sent = 0;
+        self.packets_lost = 0;
+        self.packet_loss = 0;
+        self.packet_loss_variance = 0;
+        self.packet_throttle = PEER_DEFAULT_PACKET_THROTTLE;
+        self.packet_throttle_limit = PEER_PACKET_THROTTLE_SCALE;
+        self.packet_throttle_counter = 0;
+        self.packet_throttle_epoch = 0;
+        self.packet_throttle_accel = PEER_PACKET_THROTTLE_ACCELERATION;
+        self.packet_throttle_decel = PEER_PACKET_THROTTLE_DECELERATION;
+        self.packet_throttle_interval = PEER_PACKET_THROTTLE_INTERVAL;
+        self.ping_interval = PEER_PING_INTERVAL;
+        self.timeout_limit = PEER_TIMEOUT_LIMIT;
+        self.timeout_minimum = PEER_TIMEOUT_MINIMUM;
+        self.timeout_maximum = PEER_TIMEOUT_MAXIMUM;
+        self.last_roundtrip_time = PEER_DEFAULT_ROUND_TRIP_TIME;
+        self.lowest_roundtrip_time = PEER_DEFAULT_ROUND_TRIP_TIME;
+        self.last_roundtrip_time_variance = 0;
+        self.highest_roundtrip_time_variance = 0;
+        self.roundtrip_time = PEER_DEFAULT_ROUND_TRIP_TIME;
+        self.roundtrip_time_variance = 0;
+        self.reliable_data_in_transit = 0;
+        self.outgoing_reliable_seq_num = 0;
+        self.window_size = MAXIMUM_WINDOW_SIZE;
+        self.mtu_probe = 0;
+        self.mtu_probe_seq_num = 0;
+        self.mtu_probe_limit = MAXIMUM_MTU + 1;
+        self.mtu_probe_time = 0;
+        self.incoming_unsequenced_group = 0;
+        self.outgoing_unsequenced_group = 0;
+        self.event_data = 0;
+        self.total_waiting_data = 0;
+        self.flags = 0;
+        self.unsequenced_window.fill(0);
+
+        self.reset_queues();
+    }
+
+    pub(crate) fn reset_queues(&mut self) {
+        self.flags &= !PEER_FLAG_NEEDS_DISPATCH;
+
+        self.acknowledgements.clear();
+        self.sent_reliable_commands.clear();
+        self.outgoing_commands.clear();
+        self.outgoing_send_reliable_commands.clear();
+        self.dispatched_commands.clear();
+
+        self.channels.clear();
+        self.channel_count = 0;
+    }
+
+    pub fn has_outgoing_commands(&self) -> bool {
+        !(self.outgoing_commands.is_empty() && self.outgoing_send_reliable_commands.is_empty() && self.sent_reliable_commands.is_empty())
+    }
+
+    /// Queues an acknowledgement to go out with the next datagram.
+    ///
+    /// Commands that fall just past the free reliable windows are not
+    /// acknowledged, so the sender keeps retrying them until the receiver has
+    /// made room.
+    pub fn queue_acknowledgement(&mut self, command: &Protocol, sent_time: u16) {
+        let header = command.header();
+        if let Some(channel) = self.channels.get(header.channel_id as usize) {
+            let mut reliable_window = header.reliable_sequence_number / PEER_RELIABLE_WINDOW_SIZE as u16;
+            let current_window = channel.incoming_reliable_seq_num / PEER_RELIABLE_WINDOW_SIZE as u16;
+
+            if header.reliable_sequence_number < channel.incoming_reliable_seq_num {
+                reliable_window += PEER_RELIABLE_WINDOWS as u16;
+            }
+
+            if reliable_window >= current_window + PEER_FREE_RELIABLE_WINDOWS as u16 - 1 &&
+               reliable_window <= current_window + PEER_FREE_RELIABLE_WINDOWS as u16 {
+                return;
+            }
+        }
+
+        self.outgoing_data_total = self.outgoing_data_total.wrapping_add(command_size(ProtocolCommand::Acknowledge as u8) as u32);
+
+        self.acknowledgements.push(Acknowledgement {
+            sent_time: sent_time as u32,
+            command: *command,
+        });
+    }
+
+    /// Drops an acknowledged reliable command, returning which command it was
+    pub fn remove_sent_reliable_command(&mut self, reliable_seq_num: u16, channel_id: u8) -> ProtocolCommand {
+        let matches = |cmd: &OutgoingCommand| {
+            cmd.reliable_seq_num == reliable_seq_num && cmd.command.header().channel_id == channel_id
+        };
+
+        let resent = |cmd: &OutgoingCommand| {
+            cmd.command.header().command & COMMAND_FLAG_ACKNOWLEDGE != 0 && cmd.send_attempts >= 1 && matches(cmd)
+        };
+
+        let mut outgoing_command = if let Some(index) = self.sent_reliable_commands.iter().position(matches) {
+            let outgoing_command = self.sent_reliable_commands.remove(index);
+            self.reliable_data_in_transit = self.reliable_data_in_transit.wrapping_sub(outgoing_command.fragment_length);
+            outgoing_command
+        } else if let Some(index) = self.outgoing_send_reliable_commands.iter().position(resent) {
+            // the acknowledgement raced a retransmission that was already queued again
+            self.outgoing_send_reliable_commands.remove(index)
+        } else if let Some(index) = self.outgoing_commands.iter().position(resent) {
+            self.outgoing_commands.remove(index)
+        } else {
+            return ProtocolCommand::None;
+        };
+
+        if let Some(channel) = self.channels.get_mut(channel_id as usize) {
+            let reliable_window = (reliable_seq_num / PEER_RELIABLE_WINDOW_SIZE as u16) as usize;
+            if channel.reliable_windows[reliable_window] > 0 {
+                channel.reliable_windows[reliable_window] -= 1;
+                if channel.reliable_windows[reliable_window] == 0 {
+                    channel.used_reliable_windows &= !(1 << reliable_window);
+                }
+            }
+        }
+
+        if let Some(next) = self.sent_reliable_commands.first() {
+            self.next_timeout = next.sent_time.wrapping_add(next.roundtrip_timeout);
+        }
+
+        if let Some(mut packet) = outgoing_command.packet.take() {
+            packet.mark_sent();
+        }
+
+        outgoing_command.command.command().unwrap_or(ProtocolCommand::None)
+    }
+
+    /// Buffers an incoming data command on its channel and moves whatever has
+    /// become deliverable to `dispatched_commands`.
+    ///
+    /// Duplicates and commands outside the receive window are silently
+    /// dropped; an error means the datagram should not be processed further.
+    pub fn queue_incoming_command(&mut self, command: &Protocol, data: Vec<u8>, flags: u32, fragment_count: u32, maximum_waiting_data: usize) -> Result<(), PeerError> {
+        let header = *command.header();
+        let channel_id = header.channel_id as usize;
+        let Some(channel) = self.channels.get_mut(channel_id) else {
+            return Err(PeerError::InvalidChannel(header.channel_id));
+        };
+
+        if self.state == PeerState::DisconnectLater {
+            return Ok(());
+        }
+
+        let command_number = command.command();
+        let reliable_seq_num = header.reliable_sequence_number;
+        let incoming_reliable_seq_num = channel.incoming_reliable_seq_num;
+
+        if command_number != Some(ProtocolCommand::SendUnsequenced) {
+            let mut reliable_window = reliable_seq_num / PEER_RELIABLE_WINDOW_SIZE as u16;
+            let current_window = incoming_reliable_seq_num / PEER_RELIABLE_WINDOW_SIZE as u16;
+            if reliable_seq_num < incoming_reliable_seq_num {
+                reliable_window += PEER_RELIABLE_WINDOWS as u16;
+            }
+
+            if reliable_window < current_window || reliable_window >= current_window + PEER_FREE_RELIABLE_WINDOWS as u16 - 1 {
+                return Ok(());
+            }
+        }
+
+        let mut unreliable_seq_num = 0;
+        let position = match (command_number, command) {
+            (Some(ProtocolCommand::SendReliable | ProtocolCommand::SendFragment), _) => {
+                if reliable_seq_num == incoming_reliable_seq_num {
+                    return Ok(());
+                }
+
+                // walk back from the newest buffered command to keep the queue
+                // sorted by sequence number, treating wrapped numbers as newer
+                let mut position = 0;
+                for (index, incoming_command) in channel.incoming_reliable_commands.iter().enumerate().rev() {
+                    if reliable_seq_num >= incoming_reliable_seq_num {
+                        if incoming_command.reliable_seq_num < incoming_reliable_seq_num {
+                            continue;
+                        }
+                    } else if incoming_command.reliable_seq_num >= incoming_reliable_seq_num {
+                        position = index + 1;
+                        break;
+                    }
+
+                    if incoming_command.reliable_seq_num < reliable_seq_num {
+                        position = index + 1;
+                        break;
+                    }
+
+                    if incoming_command.reliable_seq_num == reliable_seq_num {
+                        return Ok(());
+                    }
+                }
+
+                position
+            }
+            (Some(ProtocolCommand::SendUnreliable | ProtocolCommand::SendUnreliableFragment), _) => {
+                unreliable_seq_num = match command {
+                    Protocol::SendUnreliable(unreliable) => unreliable.unreliable_sequence_number,
+                    Protocol::SendFragment(fragment) => fragment.start_sequence_number,
+                    _ => return Ok(()),
+                };
+
+                if reliable_seq_num == incoming_reliable_seq_num && unreliable_seq_num <= channel.incoming_unreliable_seq_num {
+                    return Ok(());
+                }
+
+                // same ordering as the reliable queue, keyed on the reliable
+                // sequence first and the unreliable one second
+                let mut position = 0;
+                for (index, incoming_command) in channel.incoming_unreliable_commands.iter().enumerate().rev() {
+                    if incoming_command.command.command() == Some(ProtocolCommand::SendUnsequenced) {
+                        continue;
+                    }
+
+                    if reliable_seq_num >= incoming_reliable_seq_num {
+                        if incoming_command.reliable_seq_num < incoming_reliable_seq_num {
+                            continue;
+                        }
+                    } else if incoming_command.reliable_seq_num >= incoming_reliable_seq_num {
+                        position = index + 1;
+                        break;
+                    }
+
+                    if incoming_command.reliable_seq_num < reliable_seq_num {
+                        position = index + 1;
+                        break;
+                    }
+
+                    if incoming_command.reliable_seq_num > reliable_seq_num {
+                        continue;
+                    }
+
+                    if incoming_command.unreliable_seq_num < unreliable_seq_num {
+                        position = index + 1;
+                        break;
+                    }
+
+                    if incoming_command.unreliable_seq_num == unreliable_seq_num {
+                        return Ok(());
+                    }
+                }
+
+                position
+            }
+            (Some(ProtocolCommand::SendUnsequenced), _) => channel.incoming_unreliable_commands.len(),
+            _ => return Ok(()),
+        };
+
+        if self.total_waiting_data >= maximum_waiting_data {
+            return Err(PeerError::WaitingDataExceeded);
         }
 
-        let ch_id = 0;
-        if channel_id.is_none() {
-            
+        let incoming_command = IncomingCommand {
+            reliable_seq_num,
+            unreliable_seq_num,
+            command: *command,
+            fragment_count,
+            fragments_remaining: fragment_count,
+            fragments: vec![0; fragment_count.div_ceil(32) as usize],
+            packet: Packet::from_vec(data, flags),
+        };
+
+        self.total_waiting_data += incoming_command.packet.data_length();
+
+        if matches!(command_number, Some(ProtocolCommand::SendReliable | ProtocolCommand::SendFragment)) {
+            channel.incoming_reliable_commands.insert(position, incoming_command);
+            self.dispatch_incoming_reliable_commands(channel_id);
+        } else {
+            channel.incoming_unreliable_commands.insert(position, incoming_command);
+            self.dispatch_incoming_unreliable_commands(channel_id, Some(position));
         }
 
-        None
+        Ok(())
     }
 
-    pub fn on_connect(&mut self) {
-        if self.state == PEER_STATE_CONNECTED && self.state != PEER_STATE_DISCONNECT_LATER {
-            if self.incoming_bandwidth != 0 {
-                // self.host.bandwidth_limited_peers += 1;
+    /// Copies one fragment into the packet being reassembled from it, creating
+    /// that packet when the first of its fragments arrives
+    pub fn queue_incoming_fragment(&mut self, command: &Protocol, data: &[u8], maximum_packet_size: usize, maximum_waiting_data: usize) -> Result<(), PeerError> {
+        let Protocol::SendFragment(fragment) = *command else {
+            return Err(PeerError::InvalidFragment);
+        };
+
+        let channel_id = fragment.header.channel_id as usize;
+        let Some(channel) = self.channels.get(channel_id) else {
+            return Err(PeerError::InvalidChannel(fragment.header.channel_id));
+        };
+
+        // reliable fragments are ordered by the sequence number of the first
+        // one, unreliable ones by the reliable command they were sent after
+        let reliable = command.command() == Some(ProtocolCommand::SendFragment);
+        let start_seq_num = fragment.start_sequence_number;
+        let reliable_seq_num = if reliable { start_seq_num } else { fragment.header.reliable_sequence_number };
+        let incoming_reliable_seq_num = channel.incoming_reliable_seq_num;
+
+        let mut reliable_window = reliable_seq_num / PEER_RELIABLE_WINDOW_SIZE as u16;
+        let current_window = incoming_reliable_seq_num / PEER_RELIABLE_WINDOW_SIZE as u16;
+        if reliable_seq_num < incoming_reliable_seq_num {
+            reliable_window += PEER_RELIABLE_WINDOWS as u16;
+        }
+
+        if reliable_window < current_window || reliable_window >= current_window + PEER_FREE_RELIABLE_WINDOWS as u16 - 1 {
+            return Ok(());
+        }
+
+        if !reliable && reliable_seq_num == incoming_reliable_seq_num && start_seq_num <= channel.incoming_unreliable_seq_num {
+            return Ok(());
+        }
+
+        let fragment_number = fragment.fragment_number;
+        let fragment_count = fragment.fragment_count;
+        let fragment_offset = fragment.fragment_offset;
+        let total_length = fragment.total_length;
+
+        if fragment_count > MAXIMUM_FRAGMENT_COUNT || fragment_number >= fragment_count ||
+           total_length as usize > maximum_packet_size || total_length < fragment_count ||
+           fragment_offset >= total_length || data.len() > (total_length - fragment_offset) as usize {
+            return Err(PeerError::InvalidFragment);
+        }
+
+        let position = match self.find_fragment_start(channel_id, reliable, reliable_seq_num, start_seq_num, total_length, fragment_count)? {
+            Some(position) => position,
+            None => {
+                let mut start_command = *command;
+                start_command.header_mut().reliable_sequence_number = reliable_seq_num;
+
+                let flags = if reliable { PACKET_FLAG_RELIABLE } else { PACKET_FLAG_UNRELIABLE_FRAGMENT };
+                self.queue_incoming_command(&start_command, vec![0; total_length as usize], flags, fragment_count, maximum_waiting_data)?;
+
+                // the start command may have been discarded as stale
+                match self.find_fragment_start(channel_id, reliable, reliable_seq_num, start_seq_num, total_length, fragment_count)? {
+                    Some(position) => position,
+                    None => return Ok(()),
+                }
+            }
+        };
+
+        let channel = &mut self.channels[channel_id];
+        let start_command = if reliable {
+            &mut channel.incoming_reliable_commands[position]
+        } else {
+            &mut channel.incoming_unreliable_commands[position]
+        };
+
+        let word = &mut start_command.fragments[(fragment_number / 32) as usize];
+        let bit = 1 << (fragment_number % 32);
+        if *word & bit != 0 {
+            return Ok(());
+        }
+
+        *word |= bit;
+        start_command.fragments_remaining -= 1;
+
+        let offset = fragment_offset as usize;
+        let length = data.len().min(start_command.packet.data_length() - offset);
+        start_command.packet.data_mut()[offset..offset + length].copy_from_slice(&data[..length]);
+
+        if start_command.fragments_remaining == 0 {
+            if reliable {
+                self.dispatch_incoming_reliable_commands(channel_id);
+            } else {
+                self.dispatch_incoming_unreliable_commands(channel_id, None);
             }
-            // self.host.connected_peers += 1;
         }
+
+        Ok(())
     }
 
-    pub fn on_disconnect(&mut self) {
-        if self.state == PEER_STATE_CONNECTED || self.state == PEER_STATE_DISCONNECT_LATER {
-            if self.incoming_bandwidth != 0 {
-                // self.host.bandwidth_limited_peers -= 1;
+    /// Finds the buffered command that reassembles the packet a fragment
+    /// belongs to, checking that the fragment agrees with it
+    fn find_fragment_start(&self, channel_id: usize, reliable: bool, reliable_seq_num: u16, start_seq_num: u16, total_length: u32, fragment_count: u32) -> Result<Option<usize>, PeerError> {
+        let channel = &self.channels[channel_id];
+        let incoming_reliable_seq_num = channel.incoming_reliable_seq_num;
+        let (queue, command_number) = if reliable {
+            (&channel.incoming_reliable_commands, ProtocolCommand::SendFragment)
+        } else {
+            (&channel.incoming_unreliable_commands, ProtocolCommand::SendUnreliableFragment)
+        };
+
+        for (index, incoming_command) in queue.iter().enumerate().rev() {
+            if reliable_seq_num >= incoming_reliable_seq_num {
+                if incoming_command.reliable_seq_num < incoming_reliable_seq_num {
+                    continue;
+                }
+            } else if incoming_command.reliable_seq_num >= incoming_reliable_seq_num {
+                break;
+            }
+
+            if incoming_command.reliable_seq_num < reliable_seq_num {
+                break;
+            }
+
+            let seq_num = if reliable {
+                incoming_command.reliable_seq_num
+            } else {
+                if incoming_command.reliable_seq_num > reliable_seq_num {
+                    continue;
+                }
+                incoming_command.unreliable_seq_num
+            };
+
+            if seq_num < start_seq_num {
+                break;
+            }
+
+            if seq_num == start_seq_num {
+                if incoming_command.command.command() != Some(command_number) ||
+                   incoming_command.packet.data_length() != total_length as usize ||
+                   incoming_command.fragment_count != fragment_count {
+                    return Err(PeerError::InvalidFragment);
+                }
+
+                return Ok(Some(index));
             }
-            // self.host.connected_peers -= 1;
         }
+
+        Ok(None)
     }
 
-    pub fn reset(&mut self) {
-        self.on_disconnect();
+    /// Releases the run of reliable commands that directly follows the last
+    /// one delivered on the channel
+    pub fn dispatch_incoming_reliable_commands(&mut self, channel_id: usize) {
+        let channel = &mut self.channels[channel_id];
 
-        self.outgoing_peer_id = MAXIMUM_PEER_ID as u16;
-        self.connect_id = 0;
+        let mut ready = 0;
+        for incoming_command in &channel.incoming_reliable_commands {
+            if incoming_command.fragments_remaining > 0 || incoming_command.reliable_seq_num != channel.incoming_reliable_seq_num.wrapping_add(1) {
+                break;
+            }
 
-        self.state = PEER_STATE_DISCONNECTED;
+            channel.incoming_reliable_seq_num = incoming_command.reliable_seq_num;
+            if incoming_command.fragment_count > 0 {
+                channel.incoming_reliable_seq_num = channel.incoming_reliable_seq_num.wrapping_add((incoming_command.fragment_count - 1) as u16);
+            }
 
-        self.incoming_bandwidth = 0;
-        self.outgoing_bandwidth = 0;
+            ready += 1;
+        }
+
+        if ready == 0 {
+            return;
+        }
+
+        channel.incoming_unreliable_seq_num = 0;
+        self.dispatched_commands.extend(channel.incoming_reliable_commands.drain(..ready));
+
+        if !self.channels[channel_id].incoming_unreliable_commands.is_empty() {
+            self.dispatch_incoming_unreliable_commands(channel_id, None);
+        }
     }
 
-    pub fn has_outgoing_commands(&self) -> bool {
-        !(self.outgoing_commands.is_empty() && self.outgoing_send_reliable_commands.is_empty() && self.sent_reliable_commands.is_empty())
+    /// Releases unreliable commands that belong to the current reliable
+    /// sequence and drops the ones that were overtaken by it. `queued` is
+    /// the index of a command that was just inserted and must survive, so
+    /// an unfinished fragment is not thrown away before it can complete.
+    pub fn dispatch_incoming_unreliable_commands(&mut self, channel_id: usize, queued: Option<usize>) {
+        let channel = &mut self.channels[channel_id];
+        let queue = &channel.incoming_unreliable_commands;
+
+        // indices are positions in the queue; `start` opens the run waiting
+        // to be dispatched and everything before `dropped` is discarded
+        let mut dispatch = vec![false; queue.len()];
+        let mut dropped = 0;
+        let mut start = 0;
+        let mut current = 0;
+
+        while current < queue.len() {
+            let incoming_command = &queue[current];
+
+            if incoming_command.command.command() == Some(ProtocolCommand::SendUnsequenced) {
+                current += 1;
+                continue;
+            }
+
+            if incoming_command.reliable_seq_num == channel.incoming_reliable_seq_num {
+                if incoming_command.fragments_remaining == 0 {
+                    channel.incoming_unreliable_seq_num = incoming_command.unreliable_seq_num;
+                    current += 1;
+                    continue;
+                }
+
+                if start != current {
+                    dispatch[start..current].fill(true);
+                    dropped = current;
+                } else if dropped != current {
+                    dropped = current - 1;
+                }
+            } else {
+                let mut reliable_window = incoming_command.reliable_seq_num / PEER_RELIABLE_WINDOW_SIZE as u16;
+                let current_window = channel.incoming_reliable_seq_num / PEER_RELIABLE_WINDOW_SIZE as u16;
+                if incoming_command.reliable_seq_num < channel.incoming_reliable_seq_num {
+                    reliable_window += PEER_RELIABLE_WINDOWS as u16;
+                }
+
+                // still waiting on the reliable command it was sent after
+                if reliable_window >= current_window && reliable_window < current_window + PEER_FREE_RELIABLE_WINDOWS as u16 - 1 {
+                    break;
+                }
+
+                dropped = current + 1;
+                dispatch[start..current].fill(true);
+            }
+
+            current += 1;
+            start = current;
+        }
+
+        if start != current {
+            dispatch[start..current].fill(true);
+            dropped = current;
+        }
+
+        let queue = mem::take(&mut channel.incoming_unreliable_commands);
+        for (index, incoming_command) in queue.into_iter().enumerate() {
+            if dispatch[index] {
+                self.dispatched_commands.push_back(incoming_command);
+            } else if index < dropped && Some(index) != queued {
+                self.total_waiting_data -= incoming_command.packet.data_length();
+            } else {
+                channel.incoming_unreliable_commands.push_back(incoming_command);
+            }
+        }
+    }
+
+    /// Folds the packets sent and lost since the last interval into the
+    /// smoothed packet loss once `PEER_PACKET_LOSS_INTERVAL` has passed
+    pub fn update_packet_loss(&mut self, service_time: u32) {
+        if self.packet_loss_epoch == 0 {
+            self.packet_loss_epoch = service_time.max(1);
+        } else if time_difference(service_time, self.packet_loss_epoch) >= PEER_PACKET_LOSS_INTERVAL && self.packets_sent > 0 {
+            let packet_loss = (self.packets_lost as u64 * PEER_PACKET_LOSS_SCALE as u64 / self.packets_sent as u64) as u32;
+
+            self.packet_loss_variance = (self.packet_loss_variance * 3 + packet_loss.abs_diff(self.packet_loss)) / 4;
+            self.packet_loss = (self.packet_loss * 7 + packet_loss) / 8;
+
+            self.packet_loss_epoch = service_time.max(1);
+            self.packets_sent = 0;
+            self.packets_lost = 0;
+        }
+    }
+
+    pub fn stats(&self) -> PeerStats {
+        PeerStats {
+            roundtrip_time: self.roundtrip_time,
+            roundtrip_time_variance: self.roundtrip_time_variance,
+            packet_loss: self.packet_loss,
+            packet_loss_variance: self.packet_loss_variance,
+            packet_throttle: self.packet_throttle,
+        }
+    }
+
+    /// Folds a new round trip sample into the smoothed RTT and its variance
+    pub fn update_roundtrip_time(&mut self, roundtrip_time: u32, service_time: u32) {
+        let roundtrip_time = roundtrip_time.max(1);
+
+        if self.last_receive_time > 0 {
+            self.throttle(roundtrip_time);
+
+            self.roundtrip_time_variance -= self.roundtrip_time_variance / 4;
+
+            if roundtrip_time >= self.roundtrip_time {
+                let diff = roundtrip_time - self.roundtrip_time;
+                self.roundtrip_time_variance += diff / 4;
+                self.roundtrip_time += diff / 8;
+            } else {
+                let diff = self.roundtrip_time - roundtrip_time;
+                self.roundtrip_time_variance += diff / 4;
+                self.roundtrip_time -= diff / 8;
+            }
+        } else {
+            self.roundtrip_time = roundtrip_time;
+            self.roundtrip_time_variance = roundtrip_time.div_ceil(2);
+        }
+
+        self.lowest_roundtrip_time = self.lowest_roundtrip_time.min(self.roundtrip_time);
+        self.highest_roundtrip_time_variance = self.highest_roundtrip_time_variance.max(self.roundtrip_time_variance);
+
+        if self.packet_throttle_epoch == 0 || time_difference(service_time, self.packet_throttle_epoch) >= self.packet_throttle_interval {
+            self.last_roundtrip_time = self.lowest_roundtrip_time;
+            self.last_roundtrip_time_variance = self.highest_roundtrip_time_variance.max(1);
+            self.lowest_roundtrip_time = self.roundtrip_time;
+            self.highest_roundtrip_time_variance = self.roundtrip_time_variance;
+            self.packet_throttle_epoch = service_time.max(1);
+        }
+
+        self.last_receive_time = service_time.max(1);
+        self.earliest_timeout = 0;
+    }
+
+    /// Requeues every reliable command whose retransmission timer expired,
+    /// doubling its timeout. Returns true once the peer should be considered
+    /// disconnected instead.
+    pub fn check_timeouts(&mut self, service_time: u32) -> bool {
+        let mut insert_position = 0;
+        let mut insert_send_reliable_position = 0;
+        let mut index = 0;
+
+        while index < self.sent_reliable_commands.len() {
+            let outgoing_command = &self.sent_reliable_commands[index];
+
+            if time_difference(service_time, outgoing_command.sent_time) < outgoing_command.roundtrip_timeout {
+                index += 1;
+                continue;
+            }
+
+            if self.is_mtu_probe(outgoing_command.command.header().channel_id, outgoing_command.reliable_seq_num) {
+                let probe = self.sent_reliable_commands.remove(index);
+                self.reliable_data_in_transit = self.reliable_data_in_transit.wrapping_sub(probe.fragment_length);
+                self.mtu_probe_lost(service_time);
+
+                if index == 0 && let Some(next) = self.sent_reliable_commands.first() {
+                    self.next_timeout = next.sent_time.wrapping_add(next.roundtrip_timeout);
+                }
+                continue;
+            }
+
+            if self.earliest_timeout == 0 || time_less(outgoing_command.sent_time, self.earliest_timeout) {
+                // zero marks an unset timer, which a wrapped clock must not hit
+                self.earliest_timeout = outgoing_command.sent_time.max(1);
+            }
+
+            let waited = time_difference(service_time, self.earliest_timeout);
+            if self.earliest_timeout != 0 &&
+               (waited >= self.timeout_maximum ||
+                (1u32 << (outgoing_command.send_attempts - 1).min(31) >= self.timeout_limit && waited >= self.timeout_minimum)) {
+                return true;
+            }
+
+            let mut outgoing_command = self.sent_reliable_commands.remove(index);
+            self.packets_lost = self.packets_lost.wrapping_add(1);
+            outgoing_command.roundtrip_timeout = outgoing_command.roundtrip_timeout.saturating_mul(2);
+
+            // a retransmission going missing as well hints at a path that no
+            // longer carries the probed MTU
+            if outgoing_command.send_attempts >= 2 && self.mtu > self.negotiated_mtu {
+                self.mtu_fallback(service_time);
+            }
+
+            // retransmissions go out ahead of anything queued since, in their original order
+            if outgoing_command.packet.is_some() {
+                self.reliable_data_in_transit = self.reliable_data_in_transit.wrapping_sub(outgoing_command.fragment_length);
+                self.outgoing_send_reliable_commands.insert(insert_send_reliable_position, outgoing_command);
+                insert_send_reliable_position += 1;
+            } else {
+                self.outgoing_commands.insert(insert_position, outgoing_command);
+                insert_position += 1;
+            }
+
+            if index == 0 && let Some(next) = self.sent_reliable_commands.first() {
+                self.next_timeout = next.sent_time.wrapping_add(next.roundtrip_timeout);
+            }
+        }
+
+        false
+    }
+
+    /// Queues a reliable ping; its acknowledgement refreshes the round trip
+    /// time and keeps the connection from timing out
+    pub fn ping(&mut self) {
+        if self.state != PeerState::Connected {
+            return;
+        }
+
+        let command = Protocol::Ping(ProtocolPing {
+            header: ProtocolCommandHeader { command: ProtocolCommand::Ping as u8 | COMMAND_FLAG_ACKNOWLEDGE, channel_id: 0xFF, reliable_sequence_number: 0 },
+        });
+
+        self.queue_outgoing_command(command, None, 0, 0);
+    }
+
+    /// Sets how long the connection may stay quiet before the peer is
+    /// pinged, 0 restoring `PEER_PING_INTERVAL`
+    pub fn ping_interval(&mut self, ping_interval: u32) {
+        self.ping_interval = if ping_interval != 0 { ping_interval } else { PEER_PING_INTERVAL };
+    }
+
+    /// Configures when unacknowledged reliable commands end the connection.
+    ///
+    /// The peer times out once a command has gone unacknowledged for
+    /// `maximum` milliseconds, or for `minimum` milliseconds after being
+    /// retransmitted often enough for its backed off timeout to exceed
+    /// `limit` times the original. Zero restores the respective default.
+    pub fn set_timeout(&mut self, limit: u32, minimum: u32, maximum: u32) {
+        self.timeout_limit = if limit != 0 { limit } else { PEER_TIMEOUT_LIMIT };
+        self.timeout_minimum = if minimum != 0 { minimum } else { PEER_TIMEOUT_MINIMUM };
+        self.timeout_maximum = if maximum != 0 { maximum } else { PEER_TIMEOUT_MAXIMUM };
     }
 
-    // pub fn queue_outgoing_command<'a>(&mut self, command: Protocol, packet: &mut Packet<'a>, offset: u32, length: u16) -> OutgoingCommand {
-    //     if true /* maybe packet should be an option :p */ {
-    //         packet.ref_count += 1;
-    //     }
+    /// Turns automatic pings on or off. Without them an idle connection is
+    /// only noticed to be gone once something reliable is sent over it.
+    pub fn set_pinging(&mut self, enabled: bool) {
+        if enabled {
+            self.flags &= !PEER_FLAG_NO_PINGS;
+        } else {
+            self.flags |= PEER_FLAG_NO_PINGS;
+        }
+    }
+
+    /// Datagram size of the next path-MTU probe, halfway between the current
+    /// MTU and the smallest size known to fail, if one is due
+    pub fn next_mtu_probe(&self, service_time: u32) -> Option<u32> {
+        if self.state != PeerState::Connected || self.mtu_probe != 0 ||
+           self.mtu + PEER_MTU_PROBE_PRECISION >= self.mtu_probe_limit || time_less(service_time, self.mtu_probe_time) {
+            return None;
+        }
+
+        Some((self.mtu + self.mtu_probe_limit) / 2)
+    }
+
+    /// Queues a reliable ping followed by `padding`, which has to bring the
+    /// datagram to `size` bytes. Probes are never retransmitted.
+    pub fn queue_mtu_probe(&mut self, size: u32, padding: Packet) {
+        let length = padding.data_length() as u16;
+        let command = Protocol::Ping(ProtocolPing {
+            header: ProtocolCommandHeader { command: ProtocolCommand::Ping as u8 | COMMAND_FLAG_ACKNOWLEDGE, channel_id: 0xFF, reliable_sequence_number: 0 },
+        });
+
+        self.queue_outgoing_command(command, Some(padding), 0, length);
+        self.mtu_probe = size;
+        self.mtu_probe_seq_num = self.outgoing_reliable_seq_num;
+    }
+
+    pub fn is_mtu_probe(&self, channel_id: u8, reliable_seq_num: u16) -> bool {
+        self.mtu_probe != 0 && channel_id == 0xFF && reliable_seq_num == self.mtu_probe_seq_num
+    }
 
+    /// Raises the MTU to the size of the acknowledged probe
+    pub fn mtu_probe_acknowledged(&mut self) {
+        self.mtu = self.mtu.max(self.mtu_probe);
+        self.mtu_probe = 0;
+    }
 
-    // }
+    /// Caps later probes below the size of the lost one and waits
+    /// `PEER_MTU_PROBE_INTERVAL` before trying again
+    pub fn mtu_probe_lost(&mut self, service_time: u32) {
+        self.mtu_probe_limit = self.mtu_probe;
+        self.mtu_probe = 0;
+        self.mtu_probe_time = service_time.wrapping_add(PEER_MTU_PROBE_INTERVAL);
+    }
 
-    pub fn throttle_configure(&'a mut self, interval: u32, accel: u32, decel: u32) {
+    /// Returns to the negotiated MTU after datagrams of the probed size
+    /// stopped getting through, and probes again below the size that failed
+    /// after `PEER_MTU_PROBE_INTERVAL`
+    pub fn mtu_fallback(&mut self, service_time: u32) {
+        self.mtu_probe_limit = self.mtu;
+        self.mtu = self.negotiated_mtu;
+        self.mtu_probe_time = service_time.wrapping_add(PEER_MTU_PROBE_INTERVAL);
+    }
+
+    pub fn throttle_configure(&mut self, interval: u32, accel: u32, decel: u32) {
         self.packet_throttle_interval = interval;
         self.packet_throttle_accel = accel;
         self.packet_throttle_decel = decel;
 
-        let command = Protocol::ThrottleConfigure(ProtocolThrottleConfigure 
-        { 
-            header: ProtocolCommandHeader { command: ProtocolCommand::ThrottleConfigure as u8 | ProtocolCommand::Acknowledge as u8, channel_id: 0xFF, reliable_sequence_number: 0 },
+        let command = Protocol::ThrottleConfigure(ProtocolThrottleConfigure {
+            header: ProtocolCommandHeader { command: ProtocolCommand::ThrottleConfigure as u8 | COMMAND_FLAG_ACKNOWLEDGE, channel_id: 0xFF, reliable_sequence_number: 0 },
             packet_throttle_interval: interval,
             packet_throttle_acceleration: accel,
             packet_throttle_deceleration: decel,
         });
 
-        // possible impl
         self.queue_outgoing_command(command, None, 0, 0);
     }
 
-    pub fn queue_outgoing_command(&'a mut self, command: Protocol, packet: Option<Rc<RefCell<Packet<'a>>>>, offset: u32, length: u16) -> OutgoingCommand<'a> {
-        let mut cmd = OutgoingCommand::default();
+    pub fn queue_outgoing_command(&mut self, command: Protocol, packet: Option<Packet>, offset: u32, length: u16) {
+        let cmd = OutgoingCommand {
+            command,
+            fragment_offset: offset,
+            fragment_length: length as u32,
+            packet,
+            ..Default::default()
+        };
 
-        cmd.command = command;
-        cmd.fragment_offset = offset;
-        cmd.fragment_length = length as u32;
-        
-        cmd.packet = packet;
-        if let Some(pck) = &mut cmd.packet {
-            pck.borrow_mut().ref_count += 1;
-        }
-        
-        self.setup_outgoing_command(&mut cmd);
-        cmd
+        self.setup_outgoing_command(cmd);
     }
 
-    pub fn setup_outgoing_command(&mut self, cmd: &mut OutgoingCommand<'a>) {
-        self.outgoing_data_total = command_size(cmd.command.header().command) as u32 + cmd.fragment_length;
+    pub fn setup_outgoing_command(&mut self, mut cmd: OutgoingCommand) {
+        self.outgoing_data_total = self.outgoing_data_total.wrapping_add(command_size(cmd.command.header().command) as u32 + cmd.fragment_length);
 
         if cmd.command.header().channel_id == 0xFF {
-            self.outgoing_reliable_seq_num += 1;
+            self.outgoing_reliable_seq_num = self.outgoing_reliable_seq_num.wrapping_add(1);
 
             cmd.reliable_seq_num = self.outgoing_reliable_seq_num;
             cmd.unreliable_seq_num = 0;
@@ -257,19 +1136,19 @@ impl<'a> Peer<'a> {
             let channel = self.channels.get_mut(cmd.command.header().channel_id as usize).expect("failed to get channel");
 
             if cmd.command.header().command & COMMAND_FLAG_ACKNOWLEDGE != 0 {
-                channel.outgoing_reliable_seq_num += 1;
+                channel.outgoing_reliable_seq_num = channel.outgoing_reliable_seq_num.wrapping_add(1);
                 channel.outgoing_unreliable_seq_num = 0;
 
                 cmd.reliable_seq_num = channel.outgoing_reliable_seq_num;
                 cmd.unreliable_seq_num = 0;
             } else if cmd.command.header().command & COMMAND_FLAG_UNSEQUENCED != 0 {
-                self.outgoing_unsequenced_group += 1;
+                self.outgoing_unsequenced_group = self.outgoing_unsequenced_group.wrapping_add(1);
 
                 cmd.reliable_seq_num = 0;
                 cmd.unreliable_seq_num = 0;
             } else {
                 if cmd.fragment_offset == 0 {
-                    channel.outgoing_unreliable_seq_num += 1;
+                    channel.outgoing_unreliable_seq_num = channel.outgoing_unreliable_seq_num.wrapping_add(1);
                 }
 
                 cmd.reliable_seq_num = channel.outgoing_reliable_seq_num;
@@ -280,29 +1159,360 @@ impl<'a> Peer<'a> {
         cmd.send_attempts = 0;
         cmd.sent_time = 0;
         cmd.roundtrip_timeout = 0;
-        cmd.command.header_mut().reliable_sequence_number = cmd.reliable_seq_num.to_be();
-        // cmd.queue_time = self.host.total_queued + 1;
-        // self.host.total_queued += 1;
-
-        match cmd.command.header().command & ProtocolCommand::MASK {
-            val if val == ProtocolCommand::SendUnreliable as u8 => {
-                // cmd.command.header_mut().
-            },
-
-            val if val == ProtocolCommand::SendUnsequenced as u8 => {
-
-            },
+        cmd.command.header_mut().reliable_sequence_number = cmd.reliable_seq_num;
+        self.total_queued = self.total_queued.wrapping_add(1);
+        cmd.queue_time = self.total_queued;
 
+        match &mut cmd.command {
+            Protocol::SendUnreliable(unreliable) => unreliable.unreliable_sequence_number = cmd.unreliable_seq_num,
+            Protocol::SendUnsequenced(unsequenced) => unsequenced.unsequenced_group = self.outgoing_unsequenced_group,
             _ => {}
         }
 
         if cmd.command.header().command & COMMAND_FLAG_ACKNOWLEDGE != 0 &&
            cmd.packet.is_some() {
-            // push cmd
-            self.outgoing_send_reliable_commands.push(cmd.clone());
+            self.outgoing_send_reliable_commands.push(cmd);
         } else {
-            // push cmd
-            self.outgoing_commands.push(cmd.clone());
+            self.outgoing_commands.push(cmd);
         }
     }
-}
\ No newline at end of file
+}
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::protocol::{ProtocolSendFragment, ProtocolSendReliable, ProtocolSendUnreliable};
+
+    fn connected_peer() -> Peer {
+        let mut peer = Peer::new(0, SocketAddr::from(([127, 0, 0, 1], 0)));
+        peer.state = PeerState::Connected;
+        peer.channels = vec![Channel::default()];
+        peer.channel_count = 1;
+        peer
+    }
+
+    fn send_reliable(reliable_sequence_number: u16) -> Protocol {
+        Protocol::SendReliable(ProtocolSendReliable {
+            header: ProtocolCommandHeader { command: ProtocolCommand::SendReliable as u8 | COMMAND_FLAG_ACKNOWLEDGE, channel_id: 0, reliable_sequence_number },
+            data_length: 2,
+        })
+    }
+
+    fn queue(peer: &mut Peer, reliable_sequence_number: u16) {
+        peer.queue_incoming_command(&send_reliable(reliable_sequence_number), reliable_sequence_number.to_be_bytes().to_vec(), 0, 0, usize::MAX).unwrap();
+    }
+
+    fn queue_unreliable(peer: &mut Peer, reliable_sequence_number: u16, unreliable_sequence_number: u16, value: u16) {
+        let command = Protocol::SendUnreliable(ProtocolSendUnreliable {
+            header: ProtocolCommandHeader { command: ProtocolCommand::SendUnreliable as u8, channel_id: 0, reliable_sequence_number },
+            unreliable_sequence_number,
+            data_length: 2,
+        });
+        peer.queue_incoming_command(&command, value.to_be_bytes().to_vec(), 0, 0, usize::MAX).unwrap();
+    }
+
+    fn received(peer: &mut Peer) -> Vec<u16> {
+        std::iter::from_fn(|| peer.receive())
+            .map(|(_, packet)| u16::from_be_bytes([packet.data[0], packet.data[1]]))
+            .collect()
+    }
+
+    #[test]
+    fn packet_loss_rolls_over_every_interval() {
+        let mut peer = connected_peer();
+
+        peer.update_packet_loss(1000);
+        assert_eq!(peer.packet_loss_epoch, 1000);
+
+        peer.packets_sent = 8;
+        peer.packets_lost = 2;
+        peer.update_packet_loss(1000 + PEER_PACKET_LOSS_INTERVAL - 1);
+        assert_eq!(peer.packet_loss, 0);
+
+        peer.update_packet_loss(1000 + PEER_PACKET_LOSS_INTERVAL);
+        assert_eq!(peer.packet_loss, PEER_PACKET_LOSS_SCALE / 32);
+        assert_eq!(peer.packet_loss_variance, PEER_PACKET_LOSS_SCALE / 16);
+        assert_eq!((peer.packets_sent, peer.packets_lost), (0, 0));
+
+        // intervals without traffic leave the statistics alone
+        peer.update_packet_loss(1000 + 2 * PEER_PACKET_LOSS_INTERVAL);
+        assert_eq!(peer.packet_loss_epoch, 1000 + PEER_PACKET_LOSS_INTERVAL);
+
+        peer.packets_sent = 4;
+        peer.update_packet_loss(1000 + 2 * PEER_PACKET_LOSS_INTERVAL);
+        assert_eq!(peer.stats().packet_loss, PEER_PACKET_LOSS_SCALE / 32 * 7 / 8);
+        assert_eq!(peer.stats().packet_loss_variance, PEER_PACKET_LOSS_SCALE / 16 * 3 / 4 + PEER_PACKET_LOSS_SCALE / 128);
+    }
+
+    #[test]
+    fn lost_mtu_probes_cap_the_next_probe() {
+        let mut peer = connected_peer();
+        peer.mtu = 1000;
+
+        let size = peer.next_mtu_probe(0).unwrap();
+        assert_eq!(size, (1000 + peer.mtu_probe_limit) / 2);
+
+        peer.queue_mtu_probe(size, Packet::from_vec(vec![0; 100], 0));
+        assert_eq!(peer.next_mtu_probe(0), None);
+
+        let mut probe = peer.outgoing_send_reliable_commands.remove(0);
+        probe.roundtrip_timeout = 100;
+        peer.reliable_data_in_transit += probe.fragment_length;
+        peer.sent_reliable_commands.push(probe);
+
+        assert!(!peer.check_timeouts(100));
+        assert!(!peer.has_outgoing_commands());
+        assert_eq!((peer.mtu, peer.mtu_probe, peer.mtu_probe_limit), (1000, 0, size));
+        assert_eq!(peer.reliable_data_in_transit, 0);
+        assert_eq!(peer.packets_lost, 0);
+
+        assert_eq!(peer.next_mtu_probe(100), None);
+        assert_eq!(peer.next_mtu_probe(100 + PEER_MTU_PROBE_INTERVAL), Some((1000 + size) / 2));
+    }
+
+    #[test]
+    fn lossy_paths_fall_back_to_the_negotiated_mtu() {
+        let mut peer = connected_peer();
+        peer.mtu = 1000;
+        peer.negotiated_mtu = 1000;
+
+        let size = peer.next_mtu_probe(0).unwrap();
+        peer.queue_mtu_probe(size, Packet::from_vec(vec![0; 100], 0));
+        peer.mtu_probe_acknowledged();
+        peer.outgoing_send_reliable_commands.clear();
+        assert_eq!(peer.mtu, size);
+
+        // the first loss is retransmitted at the probed MTU, losing the retransmission too gives it up
+        for send_attempts in 1..=2 {
+            peer.ping();
+            let mut ping = peer.outgoing_commands.remove(0);
+            ping.send_attempts = send_attempts;
+            ping.roundtrip_timeout = 100;
+            peer.sent_reliable_commands.push(ping);
+
+            assert!(!peer.check_timeouts(100));
+            peer.outgoing_commands.clear();
+        }
+        assert_eq!((peer.mtu, peer.mtu_probe_limit), (1000, size));
+
+        assert_eq!(peer.next_mtu_probe(100), None);
+        assert_eq!(peer.next_mtu_probe(100 + PEER_MTU_PROBE_INTERVAL), Some((1000 + size) / 2));
+    }
+
+    #[test]
+    fn throttle_acceleration_saturates() {
+        let mut peer = connected_peer();
+        peer.packet_throttle = PEER_PACKET_THROTTLE_SCALE / 2;
+        peer.packet_throttle_accel = u32::MAX;
+        peer.last_roundtrip_time = 100;
+        peer.last_roundtrip_time_variance = 10;
+
+        assert_eq!(peer.throttle(50), 1);
+        assert_eq!(peer.packet_throttle, peer.packet_throttle_limit);
+    }
+
+    #[test]
+    fn retransmission_timeouts_saturate() {
+        let mut peer = connected_peer();
+        peer.timeout_limit = u32::MAX;
+        peer.timeout_maximum = u32::MAX;
+
+        peer.ping();
+        let mut ping = peer.outgoing_commands.remove(0);
+        ping.send_attempts = 1;
+        ping.roundtrip_timeout = 1 << 31;
+        peer.sent_reliable_commands.push(ping);
+
+        assert!(!peer.check_timeouts(1 << 31));
+        assert_eq!(peer.outgoing_commands[0].roundtrip_timeout, u32::MAX);
+    }
+
+    #[test]
+    fn reliable_commands_are_dispatched_in_order() {
+        let mut peer = connected_peer();
+
+        queue(&mut peer, 3);
+        queue(&mut peer, 2);
+        assert!(received(&mut peer).is_empty());
+
+        queue(&mut peer, 1);
+        queue(&mut peer, 2);
+        queue(&mut peer, 5);
+        assert_eq!(received(&mut peer), [1, 2, 3]);
+        assert_eq!(peer.channels[0].incoming_reliable_seq_num, 3);
+        assert_eq!(peer.channels[0].incoming_reliable_commands.len(), 1);
+
+        queue(&mut peer, 4);
+        assert_eq!(received(&mut peer), [4, 5]);
+        assert_eq!(peer.total_waiting_data, 0);
+    }
+
+    #[test]
+    fn reliable_sequence_numbers_wrap_around() {
+        let mut peer = connected_peer();
+        peer.channels[0].incoming_reliable_seq_num = 0xFFFD;
+
+        queue(&mut peer, 1);
+        queue(&mut peer, 0);
+        queue(&mut peer, 0xFFFF);
+        assert!(received(&mut peer).is_empty());
+
+        queue(&mut peer, 0xFFFE);
+        assert_eq!(received(&mut peer), [0xFFFE, 0xFFFF, 0, 1]);
+        assert_eq!(peer.channels[0].incoming_reliable_seq_num, 1);
+    }
+
+    #[test]
+    fn reliable_commands_outside_the_window_are_dropped() {
+        let mut peer = connected_peer();
+        let window = PEER_RELIABLE_WINDOW_SIZE as u16;
+
+        // too far ahead of the current window to be buffered
+        queue(&mut peer, (PEER_FREE_RELIABLE_WINDOWS as u16 - 1) * window);
+        assert!(peer.channels[0].incoming_reliable_commands.is_empty());
+
+        queue(&mut peer, (PEER_FREE_RELIABLE_WINDOWS as u16 - 2) * window);
+        assert_eq!(peer.channels[0].incoming_reliable_commands.len(), 1);
+
+        // already delivered
+        peer.channels[0].incoming_reliable_seq_num = 3 * window;
+        queue(&mut peer, 2 * window);
+        assert_eq!(peer.channels[0].incoming_reliable_commands.len(), 1);
+    }
+
+    #[test]
+    fn waiting_data_is_limited() {
+        let mut peer = connected_peer();
+
+        queue(&mut peer, 2);
+        let result = peer.queue_incoming_command(&send_reliable(3), vec![0, 3], 0, 0, 2);
+        assert_eq!(result, Err(PeerError::WaitingDataExceeded));
+    }
+
+    #[test]
+    fn stale_unreliable_commands_are_dropped() {
+        let mut peer = connected_peer();
+
+        queue_unreliable(&mut peer, 0, 2, 2);
+        queue_unreliable(&mut peer, 0, 1, 1);
+        queue_unreliable(&mut peer, 0, 2, 2);
+        queue_unreliable(&mut peer, 0, 4, 4);
+        assert_eq!(received(&mut peer), [2, 4]);
+        assert_eq!(peer.channels[0].incoming_unreliable_seq_num, 4);
+
+        // a command sent after a reliable one that has since been delivered
+        // belongs to an old sequence and is dropped
+        peer.channels[0].incoming_reliable_seq_num = 5;
+        peer.channels[0].incoming_unreliable_seq_num = 0;
+        queue_unreliable(&mut peer, 3, 9, 9);
+        queue_unreliable(&mut peer, 5, 1, 1);
+        assert_eq!(received(&mut peer), [1]);
+        assert!(peer.channels[0].incoming_unreliable_commands.is_empty());
+        assert_eq!(peer.total_waiting_data, 0);
+    }
+
+    #[test]
+    fn unreliable_commands_wait_for_their_reliable_command() {
+        let mut peer = connected_peer();
+
+        queue_unreliable(&mut peer, 1, 2, 12);
+        queue_unreliable(&mut peer, 1, 1, 11);
+        queue_unreliable(&mut peer, 0, 1, 1);
+        assert_eq!(received(&mut peer), [1]);
+
+        queue(&mut peer, 1);
+        assert_eq!(received(&mut peer), [1, 11, 12]);
+        assert_eq!(peer.channels[0].incoming_unreliable_seq_num, 2);
+
+        // the next reliable command resets the unreliable sequence
+        queue_unreliable(&mut peer, 1, 3, 13);
+        queue(&mut peer, 2);
+        queue_unreliable(&mut peer, 2, 1, 21);
+        assert_eq!(received(&mut peer), [13, 2, 21]);
+        assert_eq!(peer.total_waiting_data, 0);
+    }
+
+    fn fragment(fragment_number: u32, fragment_count: u32, fragment_offset: u32, total_length: u32) -> Protocol {
+        Protocol::SendFragment(ProtocolSendFragment {
+            header: ProtocolCommandHeader { command: ProtocolCommand::SendFragment as u8 | COMMAND_FLAG_ACKNOWLEDGE, channel_id: 0, reliable_sequence_number: 1 + fragment_number as u16 },
+            start_sequence_number: 1,
+            data_length: 0,
+            fragment_count,
+            fragment_number,
+            total_length,
+            fragment_offset,
+        })
+    }
+
+    #[test]
+    fn fragments_are_reassembled() {
+        let mut peer = connected_peer();
+        let data = b"fragmented!";
+
+        for (number, offset) in [(2, 8), (0, 0), (2, 8)] {
+            let end = (offset + 4).min(data.len());
+            peer.queue_incoming_fragment(&fragment(number, 3, offset as u32, data.len() as u32), &data[offset..end], usize::MAX, usize::MAX).unwrap();
+        }
+
+        assert!(peer.receive().is_none());
+        assert_eq!(peer.channels[0].incoming_reliable_commands[0].fragments_remaining, 1);
+
+        peer.queue_incoming_fragment(&fragment(1, 3, 4, data.len() as u32), &data[4..8], usize::MAX, usize::MAX).unwrap();
+        let (_, packet) = peer.receive().unwrap();
+        assert_eq!(&packet.data[..], data);
+        assert_eq!(packet.flags, crate::packet::constants::PACKET_FLAG_RELIABLE);
+        assert_eq!(peer.channels[0].incoming_reliable_seq_num, 3);
+        assert_eq!(peer.total_waiting_data, 0);
+    }
+
+    #[test]
+    fn invalid_fragments_are_rejected() {
+        let mut peer = connected_peer();
+        let invalid = [
+            fragment(3, 3, 0, 12),
+            fragment(0, 3, 12, 12),
+            fragment(0, 3, 10, 12),
+            fragment(0, 13, 0, 12),
+            fragment(0, MAXIMUM_FRAGMENT_COUNT + 1, 0, u32::MAX),
+        ];
+
+        for command in &invalid {
+            assert_eq!(peer.queue_incoming_fragment(command, &[0; 4], usize::MAX, usize::MAX), Err(PeerError::InvalidFragment));
+        }
+
+        assert_eq!(peer.queue_incoming_fragment(&fragment(0, 3, 0, 12), &[0; 4], 8, usize::MAX), Err(PeerError::InvalidFragment));
+
+        // later fragments have to agree with the first one
+        peer.queue_incoming_fragment(&fragment(0, 3, 0, 12), &[0; 4], usize::MAX, usize::MAX).unwrap();
+        assert_eq!(peer.queue_incoming_fragment(&fragment(1, 3, 4, 16), &[0; 4], usize::MAX, usize::MAX), Err(PeerError::InvalidFragment));
+        assert_eq!(peer.queue_incoming_fragment(&fragment(1, 4, 4, 12), &[0; 4], usize::MAX, usize::MAX), Err(PeerError::InvalidFragment));
+        assert!(peer.dispatched_commands.is_empty());
+    }
+
+    #[test]
+    fn roundtrip_time_smoothing() {
+        let mut peer = Peer::new(0, SocketAddr::from(([127, 0, 0, 1], 0)));
+
+        peer.update_roundtrip_time(100, 1000);
+        assert_eq!(peer.roundtrip_time, 100);
+        assert_eq!(peer.roundtrip_time_variance, 50);
+
+        peer.update_roundtrip_time(180, 1100);
+        assert_eq!(peer.roundtrip_time, 110);
+        assert_eq!(peer.roundtrip_time_variance, 58);
+
+        peer.update_roundtrip_time(20, 1200);
+        assert_eq!(peer.roundtrip_time, 99);
+        assert_eq!(peer.roundtrip_time_variance, 66);
+        assert_eq!(peer.last_receive_time, 1200);
+    }
+
+    #[test]
+    fn peer_state_transitions() {
+        assert!(PeerState::Disconnected.can_transition_to(PeerState::Connecting));
+        assert!(PeerState::Connected.can_transition_to(PeerState::Disconnecting));
+        assert!(PeerState::Zombie.can_transition_to(PeerState::Disconnected));
+        assert!(!PeerState::Disconnected.can_transition_to(PeerState::Connected));
+        assert!(!PeerState::Zombie.can_transition_to(PeerState::Connected));
+        assert!(!PeerState::Disconnecting.can_transition_to(PeerState::Connected));
+    }
+}
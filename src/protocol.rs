@@ -3,6 +3,12 @@
 //! This module contains all the protocol structures and constants used by ENet
 //! for network communication. All structures are packed and binary-compatible
 //! with the original C implementation.
+//!
+//! The structures hold their fields in host byte order; [`ProtocolHeader::decode`],
+//! [`Protocol::decode`] and their `encode` counterparts take care of converting
+//! to and from network byte order on the wire.
+
+use std::{error::Error, fmt};
 
 pub mod constants {
     pub const MINIMUM_MTU: u32 = 576;
@@ -262,7 +268,7 @@ impl Protocol {
 pub fn command_size(command_number: u8) -> usize {
     use std::mem;
 
-    match command_number {
+    match command_number & ProtocolCommand::MASK {
         0 => 0,
         1 => mem::size_of::<ProtocolAcknowledge>(),
         2 => mem::size_of::<ProtocolConnect>(),
@@ -281,6 +287,346 @@ pub fn command_size(command_number: u8) -> usize {
 }
 
 
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ProtocolError {
+    /// The datagram ended before a complete header or command could be read
+    Truncated { needed: usize, available: usize },
+    /// The command number is not one the protocol defines
+    UnknownCommand(u8),
+    /// A command claims more payload than the datagram carries
+    PayloadOutOfBounds { length: usize, available: usize },
+}
+
+impl fmt::Display for ProtocolError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ProtocolError::Truncated { needed, available } => write!(f, "truncated datagram: needed {needed} bytes, {available} available"),
+            ProtocolError::UnknownCommand(command) => write!(f, "unknown protocol command {command}"),
+            ProtocolError::PayloadOutOfBounds { length, available } => write!(f, "payload of {length} bytes exceeds the {available} remaining"),
+        }
+    }
+}
+
+impl Error for ProtocolError {}
+
+/// Big-endian cursor over a datagram that never reads out of bounds
+struct Reader<'d> {
+    data: &'d [u8],
+    position: usize,
+}
+
+impl<'d> Reader<'d> {
+    fn new(data: &'d [u8]) -> Self {
+        Self { data, position: 0 }
+    }
+
+    fn take<const N: usize>(&mut self) -> Result<[u8; N], ProtocolError> {
+        let bytes = self.data.get(self.position..self.position + N).ok_or(ProtocolError::Truncated {
+            needed: self.position + N,
+            available: self.data.len(),
+        })?;
+        self.position += N;
+
+        Ok(bytes.try_into().expect("slice length checked above"))
+    }
+
+    fn u8(&mut self) -> Result<u8, ProtocolError> {
+        Ok(self.take::<1>()?[0])
+    }
+
+    fn u16(&mut self) -> Result<u16, ProtocolError> {
+        Ok(u16::from_be_bytes(self.take()?))
+    }
+
+    fn u32(&mut self) -> Result<u32, ProtocolError> {
+        Ok(u32::from_be_bytes(self.take()?))
+    }
+}
+
+impl ProtocolHeader {
+    /// Size of the header when HEADER_FLAG_SENT_TIME is not set
+    pub const MINIMUM_SIZE: usize = 2;
+
+    /// Peer id with the flag and session bits stripped
+    pub fn id(&self) -> u16 {
+        self.peer_id & !(flags::HEADER_FLAG_MASK | flags::HEADER_SESSION_MASK)
+    }
+
+    pub fn flags(&self) -> u16 {
+        self.peer_id & flags::HEADER_FLAG_MASK
+    }
+
+    pub fn session_id(&self) -> u8 {
+        ((self.peer_id & flags::HEADER_SESSION_MASK) >> flags::HEADER_SESSION_SHIFT) as u8
+    }
+
+    /// Number of bytes this header occupies on the wire
+    pub fn size(&self) -> usize {
+        if self.flags() & flags::HEADER_FLAG_SENT_TIME != 0 {
+            std::mem::size_of::<ProtocolHeader>()
+        } else {
+            Self::MINIMUM_SIZE
+        }
+    }
+
+    /// Reads a datagram header, returning it along with its encoded size.
+    /// `sent_time` is zero when the sender did not include it.
+    pub fn decode(data: &[u8]) -> Result<(Self, usize), ProtocolError> {
+        let mut reader = Reader::new(data);
+        let mut header = Self { peer_id: reader.u16()?, sent_time: 0 };
+
+        if header.flags() & flags::HEADER_FLAG_SENT_TIME != 0 {
+            header.sent_time = reader.u16()?;
+        }
+
+        Ok((header, reader.position))
+    }
+
+    pub fn encode(&self, out: &mut Vec<u8>) {
+        let (peer_id, sent_time) = (self.peer_id, self.sent_time);
+
+        out.extend_from_slice(&peer_id.to_be_bytes());
+        if self.flags() & flags::HEADER_FLAG_SENT_TIME != 0 {
+            out.extend_from_slice(&sent_time.to_be_bytes());
+        }
+    }
+}
+
+impl Protocol {
+    /// Reads one command from the start of `data`.
+    ///
+    /// Returns the command, the payload that follows it for the `Send*`
+    /// commands (empty otherwise) and the total number of bytes consumed.
+    pub fn decode(data: &[u8]) -> Result<(Self, &[u8], usize), ProtocolError> {
+        let mut reader = Reader::new(data);
+
+        let header = ProtocolCommandHeader {
+            command: reader.u8()?,
+            channel_id: reader.u8()?,
+            reliable_sequence_number: reader.u16()?,
+        };
+
+        let command_number = header.command & ProtocolCommand::MASK;
+        let size = command_size(command_number);
+        if size == 0 || command_number >= ProtocolCommand::COUNT {
+            return Err(ProtocolError::UnknownCommand(command_number));
+        }
+        if data.len() < size {
+            return Err(ProtocolError::Truncated { needed: size, available: data.len() });
+        }
+
+        let command = match ProtocolCommand::from_u8(command_number) {
+            Some(ProtocolCommand::Acknowledge) => Protocol::Acknowledge(ProtocolAcknowledge {
+                header,
+                received_reliable_sequence_number: reader.u16()?,
+                received_sent_time: reader.u16()?,
+            }),
+            Some(ProtocolCommand::Connect) => Protocol::Connect(ProtocolConnect {
+                header,
+                outgoing_peer_id: reader.u16()?,
+                incoming_session_id: reader.u8()?,
+                outgoing_session_id: reader.u8()?,
+                mtu: reader.u32()?,
+                window_size: reader.u32()?,
+                channel_count: reader.u32()?,
+                incoming_bandwidth: reader.u32()?,
+                outgoing_bandwidth: reader.u32()?,
+                packet_throttle_interval: reader.u32()?,
+                packet_throttle_acceleration: reader.u32()?,
+                packet_throttle_deceleration: reader.u32()?,
+                connect_id: reader.u32()?,
+                data: reader.u32()?,
+            }),
+            Some(ProtocolCommand::VerifyConnect) => Protocol::VerifyConnect(ProtocolVerifyConnect {
+                header,
+                outgoing_peer_id: reader.u16()?,
+                incoming_session_id: reader.u8()?,
+                outgoing_session_id: reader.u8()?,
+                mtu: reader.u32()?,
+                window_size: reader.u32()?,
+                channel_count: reader.u32()?,
+                incoming_bandwidth: reader.u32()?,
+                outgoing_bandwidth: reader.u32()?,
+                packet_throttle_interval: reader.u32()?,
+                packet_throttle_acceleration: reader.u32()?,
+                packet_throttle_deceleration: reader.u32()?,
+                connect_id: reader.u32()?,
+            }),
+            Some(ProtocolCommand::Disconnect) => Protocol::Disconnect(ProtocolDisconnect {
+                header,
+                data: reader.u32()?,
+            }),
+            Some(ProtocolCommand::Ping) => Protocol::Ping(ProtocolPing { header }),
+            Some(ProtocolCommand::SendReliable) => Protocol::SendReliable(ProtocolSendReliable {
+                header,
+                data_length: reader.u16()?,
+            }),
+            Some(ProtocolCommand::SendUnreliable) => Protocol::SendUnreliable(ProtocolSendUnreliable {
+                header,
+                unreliable_sequence_number: reader.u16()?,
+                data_length: reader.u16()?,
+            }),
+            Some(ProtocolCommand::SendUnsequenced) => Protocol::SendUnsequenced(ProtocolSendUnsequenced {
+                header,
+                unsequenced_group: reader.u16()?,
+                data_length: reader.u16()?,
+            }),
+            Some(ProtocolCommand::SendFragment) | Some(ProtocolCommand::SendUnreliableFragment) => Protocol::SendFragment(ProtocolSendFragment {
+                header,
+                start_sequence_number: reader.u16()?,
+                data_length: reader.u16()?,
+                fragment_count: reader.u32()?,
+                fragment_number: reader.u32()?,
+                total_length: reader.u32()?,
+                fragment_offset: reader.u32()?,
+            }),
+            Some(ProtocolCommand::BandwidthLimit) => Protocol::BandwidthLimit(ProtocolBandwidthLimit {
+                header,
+                incoming_bandwidth: reader.u32()?,
+                outgoing_bandwidth: reader.u32()?,
+            }),
+            Some(ProtocolCommand::ThrottleConfigure) => Protocol::ThrottleConfigure(ProtocolThrottleConfigure {
+                header,
+                packet_throttle_interval: reader.u32()?,
+                packet_throttle_acceleration: reader.u32()?,
+                packet_throttle_deceleration: reader.u32()?,
+            }),
+            Some(ProtocolCommand::None) | None => return Err(ProtocolError::UnknownCommand(command_number)),
+        };
+
+        let payload_length = command.data_length();
+        let available = data.len() - size;
+        if payload_length > available {
+            return Err(ProtocolError::PayloadOutOfBounds { length: payload_length, available });
+        }
+
+        Ok((command, &data[size..size + payload_length], size + payload_length))
+    }
+
+    /// Appends the command, without any payload, in network byte order
+    pub fn encode(&self, out: &mut Vec<u8>) {
+        fn put_u16(out: &mut Vec<u8>, value: u16) {
+            out.extend_from_slice(&value.to_be_bytes());
+        }
+
+        fn put_u32(out: &mut Vec<u8>, value: u32) {
+            out.extend_from_slice(&value.to_be_bytes());
+        }
+
+        let header = *self.header();
+        out.push(header.command);
+        out.push(header.channel_id);
+        put_u16(out, header.reliable_sequence_number);
+
+        match *self {
+            Protocol::Header(_) | Protocol::Ping(_) => {}
+            Protocol::Acknowledge(ack) => {
+                put_u16(out, ack.received_reliable_sequence_number);
+                put_u16(out, ack.received_sent_time);
+            }
+            Protocol::Connect(connect) => {
+                put_u16(out, connect.outgoing_peer_id);
+                out.push(connect.incoming_session_id);
+                out.push(connect.outgoing_session_id);
+                put_u32(out, connect.mtu);
+                put_u32(out, connect.window_size);
+                put_u32(out, connect.channel_count);
+                put_u32(out, connect.incoming_bandwidth);
+                put_u32(out, connect.outgoing_bandwidth);
+                put_u32(out, connect.packet_throttle_interval);
+                put_u32(out, connect.packet_throttle_acceleration);
+                put_u32(out, connect.packet_throttle_deceleration);
+                put_u32(out, connect.connect_id);
+                put_u32(out, connect.data);
+            }
+            Protocol::VerifyConnect(verify) => {
+                put_u16(out, verify.outgoing_peer_id);
+                out.push(verify.incoming_session_id);
+                out.push(verify.outgoing_session_id);
+                put_u32(out, verify.mtu);
+                put_u32(out, verify.window_size);
+                put_u32(out, verify.channel_count);
+                put_u32(out, verify.incoming_bandwidth);
+                put_u32(out, verify.outgoing_bandwidth);
+                put_u32(out, verify.packet_throttle_interval);
+                put_u32(out, verify.packet_throttle_acceleration);
+                put_u32(out, verify.packet_throttle_deceleration);
+                put_u32(out, verify.connect_id);
+            }
+            Protocol::Disconnect(disconnect) => put_u32(out, disconnect.data),
+            Protocol::SendReliable(reliable) => put_u16(out, reliable.data_length),
+            Protocol::SendUnreliable(unreliable) => {
+                put_u16(out, unreliable.unreliable_sequence_number);
+                put_u16(out, unreliable.data_length);
+            }
+            Protocol::SendUnsequenced(unsequenced) => {
+                put_u16(out, unsequenced.unsequenced_group);
+                put_u16(out, unsequenced.data_length);
+            }
+            Protocol::SendFragment(fragment) => {
+                put_u16(out, fragment.start_sequence_number);
+                put_u16(out, fragment.data_length);
+                put_u32(out, fragment.fragment_count);
+                put_u32(out, fragment.fragment_number);
+                put_u32(out, fragment.total_length);
+                put_u32(out, fragment.fragment_offset);
+            }
+            Protocol::BandwidthLimit(bandwidth) => {
+                put_u32(out, bandwidth.incoming_bandwidth);
+                put_u32(out, bandwidth.outgoing_bandwidth);
+            }
+            Protocol::ThrottleConfigure(throttle) => {
+                put_u32(out, throttle.packet_throttle_interval);
+                put_u32(out, throttle.packet_throttle_acceleration);
+                put_u32(out, throttle.packet_throttle_deceleration);
+            }
+        }
+    }
+
+    /// Length of the payload trailing a `Send*` command, zero for the rest
+    pub fn data_length(&self) -> usize {
+        match *self {
+            Protocol::SendReliable(reliable) => reliable.data_length as usize,
+            Protocol::SendUnreliable(unreliable) => unreliable.data_length as usize,
+            Protocol::SendUnsequenced(unsequenced) => unsequenced.data_length as usize,
+            Protocol::SendFragment(fragment) => fragment.data_length as usize,
+            _ => 0,
+        }
+    }
+}
+
+/// Iterates over the commands of a datagram body, stopping at the first error
+pub struct Commands<'d> {
+    data: &'d [u8],
+    failed: bool,
+}
+
+impl<'d> Commands<'d> {
+    pub fn new(data: &'d [u8]) -> Self {
+        Self { data, failed: false }
+    }
+}
+
+impl<'d> Iterator for Commands<'d> {
+    type Item = Result<(Protocol, &'d [u8]), ProtocolError>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        if self.failed || self.data.is_empty() {
+            return None;
+        }
+
+        match Protocol::decode(self.data) {
+            Ok((command, payload, consumed)) => {
+                self.data = &self.data[consumed..];
+                Some(Ok((command, payload)))
+            }
+            Err(err) => {
+                self.failed = true;
+                Some(Err(err))
+            }
+        }
+    }
+}
 
 #[cfg(test)]
 mod tests {
@@ -313,4 +659,104 @@ mod tests {
         assert_eq!(flags::HEADER_FLAG_COMPRESSED, 16384);
         assert_eq!(flags::HEADER_FLAG_SENT_TIME, 32768);
     }
-}
+
+    #[test]
+    fn test_header_round_trip() {
+        let header = ProtocolHeader {
+            peer_id: 0x123 | flags::HEADER_FLAG_SENT_TIME | (2 << flags::HEADER_SESSION_SHIFT),
+            sent_time: 0xBEEF,
+        };
+
+        let mut out = Vec::new();
+        header.encode(&mut out);
+        assert_eq!(out, [0xA1, 0x23, 0xBE, 0xEF]);
+
+        let (decoded, size) = ProtocolHeader::decode(&out).unwrap();
+        assert_eq!(size, 4);
+        assert_eq!(decoded.id(), 0x123);
+        assert_eq!(decoded.session_id(), 2);
+        assert_eq!(decoded.flags(), flags::HEADER_FLAG_SENT_TIME);
+        assert_eq!({ decoded.sent_time }, 0xBEEF);
+
+        let (short, size) = ProtocolHeader::decode(&[0x00, 0x05, 0xFF]).unwrap();
+        assert_eq!(size, ProtocolHeader::MINIMUM_SIZE);
+        assert_eq!(short.id(), 5);
+
+        assert_eq!(ProtocolHeader::decode(&[0x80, 0x01, 0x00]).unwrap_err(), ProtocolError::Truncated { needed: 4, available: 3 });
+    }
+
+    #[test]
+    fn test_command_round_trip() {
+        let connect = Protocol::Connect(ProtocolConnect {
+            header: ProtocolCommandHeader { command: ProtocolCommand::Connect as u8 | flags::COMMAND_FLAG_ACKNOWLEDGE, channel_id: 0xFF, reliable_sequence_number: 1 },
+            outgoing_peer_id: 7,
+            incoming_session_id: 0xFF,
+            outgoing_session_id: 0xFF,
+            mtu: 1392,
+            window_size: 32768,
+            channel_count: 2,
+            incoming_bandwidth: 0,
+            outgoing_bandwidth: 0,
+            packet_throttle_interval: 5000,
+            packet_throttle_acceleration: 2,
+            packet_throttle_deceleration: 2,
+            connect_id: 0xDEADBEEF,
+            data: 42,
+        });
+
+        let mut out = Vec::new();
+        connect.encode(&mut out);
+        assert_eq!(out.len(), command_size(ProtocolCommand::Connect as u8));
+        assert_eq!(&out[..8], [0x82, 0xFF, 0x00, 0x01, 0x00, 0x07, 0xFF, 0xFF]);
+
+        let (decoded, payload, consumed) = Protocol::decode(&out).unwrap();
+        assert!(payload.is_empty());
+        assert_eq!(consumed, out.len());
+        match decoded {
+            Protocol::Connect(decoded) => {
+                assert_eq!({ decoded.mtu }, 1392);
+                assert_eq!({ decoded.connect_id }, 0xDEADBEEF);
+                assert_eq!({ decoded.data }, 42);
+            }
+            _ => panic!("decoded the wrong command"),
+        }
+    }
+
+    #[test]
+    fn test_command_payload_bounds() {
+        let send = Protocol::SendReliable(ProtocolSendReliable {
+            header: ProtocolCommandHeader { command: ProtocolCommand::SendReliable as u8, channel_id: 0, reliable_sequence_number: 3 },
+            data_length: 4,
+        });
+
+        let mut out = Vec::new();
+        send.encode(&mut out);
+        out.extend_from_slice(b"asdf");
+
+        let (_, payload, consumed) = Protocol::decode(&out).unwrap();
+        assert_eq!(payload, b"asdf");
+        assert_eq!(consumed, 10);
+
+        assert_eq!(Protocol::decode(&out[..8]).unwrap_err(), ProtocolError::PayloadOutOfBounds { length: 4, available: 2 });
+        assert_eq!(Protocol::decode(&out[..5]).unwrap_err(), ProtocolError::Truncated { needed: 6, available: 5 });
+        assert_eq!(Protocol::decode(&[0x0E, 0, 0, 0]).unwrap_err(), ProtocolError::UnknownCommand(0x0E));
+        assert_eq!(Protocol::decode(&[0x00, 0, 0, 0]).unwrap_err(), ProtocolError::UnknownCommand(0));
+    }
+
+    #[test]
+    fn test_commands_iterator_stops_on_error() {
+        let ping = Protocol::Ping(ProtocolPing {
+            header: ProtocolCommandHeader { command: ProtocolCommand::Ping as u8 | flags::COMMAND_FLAG_ACKNOWLEDGE, channel_id: 0xFF, reliable_sequence_number: 9 },
+        });
+
+        let mut out = Vec::new();
+        ping.encode(&mut out);
+        ping.encode(&mut out);
+        out.extend_from_slice(&[0x06, 0x00]);
+
+        let commands: Vec<_> = Commands::new(&out).collect();
+        assert_eq!(commands.len(), 3);
+        assert!(commands[0].is_ok() && commands[1].is_ok());
+        assert!(commands[2].is_err());
+    }
+}
\ No newline at end of file
@@ -0,0 +1,395 @@
+//! Deterministic in-memory network for testing hosts
+//!
+//! The simulator drives sans-IO hosts one millisecond at a time off a shared
+//! [`MockClock`], carrying their datagrams over links with configurable
+//! latency, jitter, loss, duplication, reordering, bandwidth and MTU. Everything
+//! random comes from a seeded generator, so a test replays identically for
+//! the same seed.
+
+use std::{cmp::Reverse, collections::{BinaryHeap, HashMap, VecDeque}, io, net::{Ipv4Addr, SocketAddr}};
+
+use crate::{event::Event, host::{Host, Transmit}, peer::PeerId, time::{Clock, MockClock}};
+
+pub mod constants {
+    pub const SIMULATOR_PORT: u16 = 7777;
+}
+
+/// Conditions a link applies to every datagram it carries. Probabilities
+/// range from 0.0 to 1.0, times are in milliseconds.
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub struct LinkConditions {
+    pub latency: u32,
+    /// Extra delay of up to this many milliseconds, picked per datagram
+    pub jitter: u32,
+    pub loss: f64,
+    pub duplication: f64,
+    /// Chance of a datagram being held back behind the ones sent after it
+    pub reordering: f64,
+    /// Bytes per second, zero meaning unlimited
+    pub bandwidth: u32,
+    /// Largest datagram in bytes the link carries, dropping anything bigger.
+    /// Zero carries datagrams of any size.
+    pub mtu: u32,
+}
+
+/// State of one direction between two hosts
+#[derive(Default)]
+struct Link {
+    busy_until: u64,
+    last_delivery: u64,
+}
+
+struct InFlight {
+    to: usize,
+    from: SocketAddr,
+    data: Vec<u8>,
+}
+
+/// Hosts connected through simulated links
+pub struct Simulator {
+    pub clock: MockClock,
+    pub hosts: Vec<Host>,
+    /// Conditions of every link without its own
+    pub conditions: LinkConditions,
+    pub sent_datagrams: u64,
+    pub dropped_datagrams: u64,
+    pub duplicated_datagrams: u64,
+
+    elapsed: u64,
+    random_seed: u64,
+    link_conditions: HashMap<(usize, usize), LinkConditions>,
+    links: HashMap<(usize, usize), Link>,
+    schedule: BinaryHeap<Reverse<(u64, u64)>>,
+    in_flight: HashMap<u64, InFlight>,
+    next_datagram: u64,
+    events: VecDeque<(usize, Event)>,
+}
+
+impl Simulator {
+    pub fn new(seed: u64) -> Self {
+        Self {
+            clock: MockClock::new(0),
+            hosts: Vec::new(),
+            conditions: LinkConditions::default(),
+            sent_datagrams: 0,
+            dropped_datagrams: 0,
+            duplicated_datagrams: 0,
+            elapsed: 0,
+            random_seed: seed,
+            link_conditions: HashMap::new(),
+            links: HashMap::new(),
+            schedule: BinaryHeap::new(),
+            in_flight: HashMap::new(),
+            next_datagram: 0,
+            events: VecDeque::new(),
+        }
+    }
+
+    /// Adds a host like `Host::new` and returns its index
+    pub fn add_host(&mut self, peer_count: usize, channel_limit: usize, incoming_bandwidth: u32, outgoing_bandwidth: u32) -> io::Result<usize> {
+        let index = self.hosts.len();
+        let address = SocketAddr::from((Ipv4Addr::from(0x0A00_0001 + index as u32), constants::SIMULATOR_PORT));
+
+        let mut host = Host::new(address, peer_count, channel_limit, incoming_bandwidth, outgoing_bandwidth)?;
+        host.set_clock(Box::new(self.clock.clone()));
+        // connect ids come from the host's generator, which has to replay too
+        host.random_seed = self.random() as u32;
+
+        self.hosts.push(host);
+        Ok(index)
+    }
+
+    pub fn host(&self, index: usize) -> &Host {
+        &self.hosts[index]
+    }
+
+    pub fn host_mut(&mut self, index: usize) -> &mut Host {
+        &mut self.hosts[index]
+    }
+
+    /// Starts connecting host `from` to host `to`
+    pub fn connect(&mut self, from: usize, to: usize, channel_count: usize, data: u32) -> Option<PeerId> {
+        let address = self.hosts[to].address;
+        self.hosts[from].connect(address, channel_count, data)
+    }
+
+    /// Overrides the conditions of the link carrying datagrams from host
+    /// `from` to host `to`
+    pub fn set_link(&mut self, from: usize, to: usize, conditions: LinkConditions) {
+        self.link_conditions.insert((from, to), conditions);
+    }
+
+    /// Milliseconds simulated so far
+    pub fn elapsed(&self) -> u64 {
+        self.elapsed
+    }
+
+    /// Simulates `milliseconds` of time, queueing the events raised meanwhile
+    pub fn run(&mut self, milliseconds: u32) {
+        for _ in 0..milliseconds {
+            self.step();
+        }
+    }
+
+    /// Returns the next event of any host along with the host's index,
+    /// simulating up to `timeout` milliseconds for one to happen
+    pub fn next_event(&mut self, timeout: u32) -> Option<(usize, Event)> {
+        for _ in 0..timeout {
+            if !self.events.is_empty() {
+                break;
+            }
+            self.step();
+        }
+
+        self.events.pop_front()
+    }
+
+    /// Advances the clock by a millisecond, delivering the datagrams that
+    /// arrived and sending whatever the hosts have queued
+    fn step(&mut self) {
+        self.elapsed += 1;
+        self.clock.advance(1);
+        let now = self.clock.now();
+
+        while let Some(&Reverse((deliver_at, id))) = self.schedule.peek() && deliver_at <= self.elapsed {
+            self.schedule.pop();
+            if let Some(datagram) = self.in_flight.remove(&id) {
+                self.hosts[datagram.to].handle_datagram(now, datagram.from, &datagram.data);
+            }
+        }
+
+        for index in 0..self.hosts.len() {
+            while let Some(transmit) = self.hosts[index].poll_transmit(now) {
+                self.transmit(index, transmit);
+            }
+
+            while let Some(event) = self.hosts[index].poll_event() {
+                self.events.push_back((index, event));
+            }
+        }
+    }
+
+    fn transmit(&mut self, from: usize, transmit: Transmit) {
+        self.sent_datagrams += 1;
+
+        // datagrams to addresses no host owns vanish like on a real network
+        let Some(to) = self.hosts.iter().position(|host| host.address == transmit.address) else {
+            self.dropped_datagrams += 1;
+            return;
+        };
+
+        let conditions = self.link_conditions.get(&(from, to)).copied().unwrap_or(self.conditions);
+        if self.chance(conditions.loss) || (conditions.mtu > 0 && transmit.data.len() > conditions.mtu as usize) {
+            self.dropped_datagrams += 1;
+            return;
+        }
+
+        let link = self.links.entry((from, to)).or_default();
+        let mut departure = self.elapsed.max(link.busy_until);
+        if conditions.bandwidth > 0 {
+            departure += (transmit.data.len() as u64 * 1000).div_ceil(conditions.bandwidth as u64);
+            link.busy_until = departure;
+        }
+
+        let copies = if self.chance(conditions.duplication) {
+            self.duplicated_datagrams += 1;
+            2
+        } else {
+            1
+        };
+
+        let from_address = self.hosts[from].address;
+        for _ in 0..copies {
+            let mut deliver_at = departure + conditions.latency as u64 + self.random_below(conditions.jitter as u64 + 1);
+
+            if self.chance(conditions.reordering) {
+                deliver_at += 1 + self.random_below(conditions.latency as u64 + conditions.jitter as u64 + 1);
+            } else {
+                // jitter alone never lets a datagram overtake an earlier one
+                let link = self.links.entry((from, to)).or_default();
+                deliver_at = deliver_at.max(link.last_delivery);
+                link.last_delivery = deliver_at;
+            }
+
+            let id = self.next_datagram;
+            self.next_datagram += 1;
+            self.schedule.push(Reverse((deliver_at, id)));
+            self.in_flight.insert(id, InFlight { to, from: from_address, data: transmit.data.clone() });
+        }
+    }
+
+    /// SplitMix64
+    fn random(&mut self) -> u64 {
+        self.random_seed = self.random_seed.wrapping_add(0x9E3779B97F4A7C15);
+        let mut z = self.random_seed;
+        z = (z ^ (z >> 30)).wrapping_mul(0xBF58476D1CE4E5B9);
+        z = (z ^ (z >> 27)).wrapping_mul(0x94D049BB133111EB);
+        z ^ (z >> 31)
+    }
+
+    fn random_below(&mut self, limit: u64) -> u64 {
+        if limit <= 1 {
+            return 0;
+        }
+        self.random() % limit
+    }
+
+    fn chance(&mut self, probability: f64) -> bool {
+        probability > 0.0 && ((self.random() >> 11) as f64 / (1u64 << 53) as f64) < probability
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{event::EventType, packet::{constants::PACKET_FLAG_RELIABLE, Packet}, peer::{constants::{PEER_MTU_PROBE_PRECISION, PEER_TIMEOUT_MAXIMUM, PEER_TIMEOUT_MINIMUM}, PeerState}};
+
+    const HOSTILE: LinkConditions = LinkConditions {
+        latency: 30,
+        jitter: 20,
+        loss: 0.2,
+        duplication: 0.1,
+        reordering: 0.2,
+        bandwidth: 0,
+        mtu: 0,
+    };
+
+    /// Connects host 1 to host 0 and returns the client's peer id
+    fn connected(simulator: &mut Simulator, channel_count: usize) -> PeerId {
+        simulator.add_host(1, channel_count, 0, 0).unwrap();
+        simulator.add_host(1, channel_count, 0, 0).unwrap();
+
+        let peer = simulator.connect(1, 0, channel_count, 0).unwrap();
+        let mut connected = 0;
+        while connected < 2 {
+            let (_, event) = simulator.next_event(60_000).expect("hosts never connected");
+            assert_eq!(event.event_type, EventType::Connect);
+            connected += 1;
+        }
+        peer
+    }
+
+    /// Collects the payloads host 0 receives until `count` have arrived
+    fn receive(simulator: &mut Simulator, count: usize) -> Vec<Vec<u8>> {
+        let mut received = Vec::new();
+        while received.len() < count {
+            let (index, event) = simulator.next_event(60_000).expect("packets never arrived");
+            assert_eq!((index, event.event_type), (0, EventType::Receive));
+            received.push(event.packet.unwrap().data.to_vec());
+        }
+        received
+    }
+
+    #[test]
+    fn reliable_packets_arrive_in_order_over_a_hostile_link() {
+        let mut simulator = Simulator::new(1);
+        simulator.conditions = HOSTILE;
+        let peer = connected(&mut simulator, 1);
+
+        for i in 0..100u32 {
+            simulator.host_mut(1).send(peer, 0, Packet::from_vec(i.to_be_bytes().to_vec(), PACKET_FLAG_RELIABLE)).unwrap();
+        }
+
+        let received = receive(&mut simulator, 100);
+        let expected: Vec<_> = (0..100u32).map(|i| i.to_be_bytes().to_vec()).collect();
+        assert_eq!(received, expected);
+        assert!(simulator.dropped_datagrams > 0);
+        assert!(simulator.duplicated_datagrams > 0);
+    }
+
+    #[test]
+    fn fragmented_packets_are_reassembled_over_a_hostile_link() {
+        let mut simulator = Simulator::new(2);
+        simulator.conditions = HOSTILE;
+        let peer = connected(&mut simulator, 1);
+
+        let payload: Vec<u8> = (0..20_000u32).map(|i| (i % 251) as u8).collect();
+        simulator.host_mut(1).send(peer, 0, Packet::from_vec(payload.clone(), PACKET_FLAG_RELIABLE)).unwrap();
+
+        assert_eq!(receive(&mut simulator, 1), [payload]);
+    }
+
+    #[test]
+    fn bandwidth_caps_delay_delivery() {
+        let mut simulator = Simulator::new(3);
+        simulator.conditions = LinkConditions { bandwidth: 10_000, ..LinkConditions::default() };
+        let peer = connected(&mut simulator, 1);
+
+        let start = simulator.elapsed();
+        simulator.host_mut(1).send(peer, 0, Packet::from_vec(vec![7; 5000], PACKET_FLAG_RELIABLE)).unwrap();
+        receive(&mut simulator, 1);
+
+        assert!(simulator.elapsed() - start >= 500);
+    }
+
+    #[test]
+    fn peers_time_out_when_the_link_goes_down() {
+        let mut simulator = Simulator::new(4);
+        let peer = connected(&mut simulator, 1);
+
+        simulator.conditions.loss = 1.0;
+        simulator.host_mut(1).send(peer, 0, Packet::from_vec(vec![1], PACKET_FLAG_RELIABLE)).unwrap();
+
+        // both ends give up on their unacknowledged commands, the client's packet and the server's pings
+        let start = simulator.elapsed();
+        let mut timed_out = [false; 2];
+        while !timed_out.iter().all(|&timed_out| timed_out) {
+            let (index, event) = simulator.next_event(60_000).expect("peers never timed out");
+            assert_eq!(event.event_type, EventType::DisconnectTimeout);
+            timed_out[index] = true;
+
+            let waited = simulator.elapsed() - start;
+            assert!((PEER_TIMEOUT_MINIMUM as u64..=2 * PEER_TIMEOUT_MAXIMUM as u64).contains(&waited), "timed out after {waited}ms");
+        }
+        assert_eq!(simulator.host(1).peer(peer).state(), PeerState::Disconnected);
+    }
+
+    #[test]
+    fn mtu_probing_finds_the_path_mtu_and_falls_back_when_it_shrinks() {
+        let mut simulator = Simulator::new(7);
+        simulator.conditions.mtu = 2000;
+        let peer = connected(&mut simulator, 1);
+        simulator.host_mut(1).mtu_probing = true;
+
+        simulator.run(60_000);
+        let mtu = simulator.host(1).peer(peer).mtu;
+        assert!((2000 - PEER_MTU_PROBE_PRECISION..=2000).contains(&mtu), "mtu {mtu}");
+
+        // datagrams batched for the probed MTU no longer get through, the
+        // fragments of a large packet were cut small enough to still fit
+        let negotiated_mtu = simulator.host(1).peer(peer).negotiated_mtu;
+        simulator.conditions.mtu = negotiated_mtu;
+
+        let mut sent: Vec<Vec<u8>> = (0..20u8).map(|i| vec![i; 200]).collect();
+        sent.push((0..5000u32).map(|i| (i % 251) as u8).collect());
+        for data in &sent {
+            simulator.host_mut(1).send(peer, 0, Packet::from_vec(data.clone(), PACKET_FLAG_RELIABLE)).unwrap();
+        }
+
+        assert_eq!(receive(&mut simulator, sent.len()), sent);
+        assert_eq!(simulator.host(1).peer(peer).mtu, negotiated_mtu);
+    }
+
+    #[test]
+    fn runs_replay_for_the_same_seed() {
+        fn trace(seed: u64) -> Vec<(u64, usize, EventType)> {
+            let mut simulator = Simulator::new(seed);
+            simulator.conditions = HOSTILE;
+            let peer = connected(&mut simulator, 2);
+
+            for i in 0..20u8 {
+                let packet = Packet::from_vec(vec![i; 100 * i as usize + 1], PACKET_FLAG_RELIABLE);
+                simulator.host_mut(1).send(peer, i % 2, packet).unwrap();
+            }
+
+            let mut trace = Vec::new();
+            while let Some((index, event)) = simulator.next_event(5000) {
+                trace.push((simulator.elapsed(), index, event.event_type));
+            }
+            trace
+        }
+
+        assert_eq!(trace(5), trace(5));
+        assert_ne!(trace(5), trace(6));
+    }
+}
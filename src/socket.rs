@@ -0,0 +1,80 @@
+use std::{fmt, hash::Hash, io, net::{SocketAddr, UdpSocket}, thread, time::Duration};
+
+/// Identifies the remote end of a transport, like a `SocketAddr` for UDP
+pub trait Address: Clone + PartialEq + Hash + fmt::Debug + Send + 'static {
+    /// Whether both addresses belong to the same machine, which limits how
+    /// many peers it may connect with (see `Host::duplicate_peers`)
+    fn same_host(&self, other: &Self) -> bool {
+        self == other
+    }
+}
+
+impl Address for SocketAddr {
+    fn same_host(&self, other: &Self) -> bool {
+        self.ip() == other.ip()
+    }
+}
+
+/// Datagram transport a host sends and receives through.
+///
+/// Datagrams may be lost, duplicated or reordered, the protocol takes care
+/// of that; they only have to arrive whole or not at all.
+pub trait Socket: Send {
+    type Address: Address;
+
+    fn local_address(&self) -> io::Result<Self::Address>;
+
+    /// Sends a datagram without blocking. `WouldBlock` is treated like any
+    /// other lost datagram.
+    fn send_to(&mut self, data: &[u8], address: &Self::Address) -> io::Result<usize>;
+
+    /// Receives a datagram without blocking, failing with `WouldBlock` when
+    /// none is waiting
+    fn recv_from(&mut self, buffer: &mut [u8]) -> io::Result<(usize, Self::Address)>;
+
+    /// Waits up to `timeout` milliseconds for a datagram to arrive, returning
+    /// whether one might be waiting. The default polls every millisecond.
+    fn wait(&mut self, timeout: u32) -> io::Result<bool> {
+        if timeout > 0 {
+            thread::sleep(Duration::from_millis(1));
+        }
+        Ok(timeout > 0)
+    }
+}
+
+impl Socket for UdpSocket {
+    type Address = SocketAddr;
+
+    fn local_address(&self) -> io::Result<SocketAddr> {
+        self.local_addr()
+    }
+
+    fn send_to(&mut self, data: &[u8], address: &SocketAddr) -> io::Result<usize> {
+        UdpSocket::send_to(self, data, address)
+    }
+
+    fn recv_from(&mut self, buffer: &mut [u8]) -> io::Result<(usize, SocketAddr)> {
+        UdpSocket::recv_from(self, buffer)
+    }
+
+    fn wait(&mut self, timeout: u32) -> io::Result<bool> {
+        if timeout == 0 {
+            return Ok(false);
+        }
+
+        self.set_nonblocking(false)?;
+        self.set_read_timeout(Some(Duration::from_millis(timeout as u64)))?;
+
+        let mut probe = [0u8; 1];
+        let result = self.peek_from(&mut probe);
+        self.set_nonblocking(true)?;
+
+        match result {
+            Ok(_) => Ok(true),
+            Err(err) if err.kind() == io::ErrorKind::WouldBlock || err.kind() == io::ErrorKind::TimedOut => Ok(false),
+            // a truncated peek still means a datagram is waiting, and an
+            // interrupted wait simply goes around the service loop again
+            Err(_) => Ok(true),
+        }
+    }
+}
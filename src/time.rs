@@ -0,0 +1,126 @@
+//! Millisecond timestamps used throughout the protocol
+//!
+//! ENet keeps all of its timers as wrapping 32-bit millisecond counters, so
+//! comparisons have to go through these helpers rather than plain `<`/`>`.
+//! Where those timestamps come from is up to the host's [`Clock`].
+
+use std::sync::Arc;
+use std::sync::atomic::{AtomicU32, Ordering};
+use std::time::Instant;
+
+pub mod constants {
+    pub const TIME_OVERFLOW: u32 = 86400000;
+}
+
+pub fn time_less(a: u32, b: u32) -> bool {
+    a.wrapping_sub(b) >= constants::TIME_OVERFLOW
+}
+
+pub fn time_greater(a: u32, b: u32) -> bool {
+    b.wrapping_sub(a) >= constants::TIME_OVERFLOW
+}
+
+pub fn time_less_equal(a: u32, b: u32) -> bool {
+    !time_greater(a, b)
+}
+
+pub fn time_greater_equal(a: u32, b: u32) -> bool {
+    !time_less(a, b)
+}
+
+pub fn time_difference(a: u32, b: u32) -> u32 {
+    if a.wrapping_sub(b) >= constants::TIME_OVERFLOW {
+        b.wrapping_sub(a)
+    } else {
+        a.wrapping_sub(b)
+    }
+}
+
+/// Source of the millisecond timestamps a host works with.
+///
+/// Only differences between timestamps matter, so a clock may start at any
+/// value and is expected to wrap around past `u32::MAX`.
+pub trait Clock: Send {
+    fn now(&self) -> u32;
+}
+
+/// Monotonic clock counting milliseconds since it was created
+pub struct SystemClock {
+    epoch: Instant,
+}
+
+impl SystemClock {
+    pub fn new() -> Self {
+        Self { epoch: Instant::now() }
+    }
+}
+
+impl Default for SystemClock {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Clock for SystemClock {
+    fn now(&self) -> u32 {
+        self.epoch.elapsed().as_millis() as u32
+    }
+}
+
+/// Clock that only moves when told to, for testing timers without sleeping.
+/// Clones share the same time.
+#[derive(Clone, Default)]
+pub struct MockClock {
+    time: Arc<AtomicU32>,
+}
+
+impl MockClock {
+    pub fn new(time: u32) -> Self {
+        Self { time: Arc::new(AtomicU32::new(time)) }
+    }
+
+    pub fn set(&self, time: u32) {
+        self.time.store(time, Ordering::Relaxed);
+    }
+
+    /// Moves the clock forward, wrapping around like a real millisecond counter
+    pub fn advance(&self, milliseconds: u32) {
+        self.time.fetch_add(milliseconds, Ordering::Relaxed);
+    }
+}
+
+impl Clock for MockClock {
+    fn now(&self) -> u32 {
+        self.time.load(Ordering::Relaxed)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn comparisons_wrap_around() {
+        let before = u32::MAX - 10;
+        let after = 20;
+
+        assert!(time_less(before, after));
+        assert!(time_greater(after, before));
+        assert!(time_less_equal(before, before));
+        assert!(time_greater_equal(after, before));
+        assert_eq!(time_difference(after, before), 31);
+        assert_eq!(time_difference(before, after), 31);
+    }
+
+    #[test]
+    fn mock_clock_is_shared_between_clones() {
+        let clock = MockClock::new(u32::MAX - 1);
+        let other = clock.clone();
+
+        other.advance(3);
+        assert_eq!(clock.now(), 1);
+
+        clock.set(500);
+        assert_eq!(other.now(), 500);
+    }
+}